@@ -0,0 +1,433 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Neg};
+use std::str::FromStr;
+use std::f64::consts::LOG2_10;
+
+/// An arbitrary precision signed number.
+///
+/// Stored as fixed point, with `frac` 32 bit limbs after the binary point.
+/// That is a poor fit for huge numbers but exactly what fractal coordinates
+/// need, where everything interesting lies within a few units of the origin
+/// and all the precision has to go after the point. Results of arithmetic
+/// take the larger precision of the two sides and are truncated to it.
+#[derive(Clone, Debug)]
+pub struct BigFloat {
+    neg: bool,
+    /// Little endian magnitude, the lowest `frac` limbs are after the point
+    limbs: Vec<u32>,
+    frac: usize,
+}
+
+/// Largest decimal exponent `from_str` accepts either way. Every power of
+/// ten is spelled out in digits, so without a limit a short string could ask
+/// for more memory than there is. Ten thousand is still far deeper than any
+/// zoom can be rendered.
+const MAX_EXPONENT: i64 = 10_000;
+
+/// Number of limbs needed to hold `bits` bits after the point.
+fn limbs_for(bits: usize) -> usize {
+    bits.div_ceil(32).max(2)
+}
+
+/// Number of decimal digits needed after the point for `frac` limbs to round trip.
+fn digits_for(frac: usize) -> usize {
+    (frac as f64 * 32.0 / LOG2_10).ceil() as usize + 1
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    let len = a.len().max(b.len());
+    for i in (0..len).rev() {
+        let x = a.get(i).cloned().unwrap_or(0);
+        let y = b.get(i).cloned().unwrap_or(0);
+        if x != y { return x.cmp(&y) }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let len = a.len().max(b.len());
+    let mut out = Vec::with_capacity(len + 1);
+    let mut carry = 0u64;
+    for i in 0..len {
+        let sum = a.get(i).cloned().unwrap_or(0) as u64 + b.get(i).cloned().unwrap_or(0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry != 0 { out.push(carry as u32); }
+    out
+}
+
+/// `a - b`, where `a` must be at least as large as `b`.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &limb) in a.iter().enumerate() {
+        let mut diff = limb as i64 - b.get(i).cloned().unwrap_or(0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    out
+}
+
+impl BigFloat {
+    pub fn zero(bits: usize) -> BigFloat {
+        let frac = limbs_for(bits);
+        BigFloat { neg: false, limbs: vec![0; frac], frac }
+    }
+
+    pub fn from_f64(v: f64, bits: usize) -> BigFloat {
+        let mut out = BigFloat::zero(bits);
+        if v == 0.0 || !v.is_finite() { return out }
+
+        let raw = v.abs().to_bits();
+        let exp = ((raw >> 52) & 0x7ff) as i64;
+        let mantissa = if exp == 0 { raw & ((1 << 52) - 1) } else { (raw & ((1 << 52) - 1)) | (1 << 52) };
+        // v = mantissa * 2^(exp - 1075), and we want v * 2^(32*frac)
+        let shift = exp.max(1) - 1075 + 32 * out.frac as i64;
+
+        if shift < 0 {
+            if shift > -64 { out.add_at(0, mantissa >> -shift); }
+        } else {
+            let word = (shift / 32) as usize;
+            let wide = (mantissa as u128) << (shift % 32);
+            for i in 0..3 {
+                let limb = (wide >> (32 * i)) as u32;
+                if limb != 0 {
+                    if out.limbs.len() <= word + i { out.limbs.resize(word + i + 1, 0); }
+                    out.limbs[word + i] = limb;
+                }
+            }
+        }
+        out.neg = v < 0.0;
+        out
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let top = match self.limbs.iter().rposition(|&l| l != 0) {
+            Some(top) => top,
+            None => return 0.0,
+        };
+        let mut v = 0.0;
+        let low = top.saturating_sub(2);
+        for i in (low..=top).rev() {
+            v = v * 4294967296.0 + self.limbs[i] as f64;
+        }
+        // Scaled in two steps so that tiny values don't underflow early
+        let e = 32 * (low as i32 - self.frac as i32);
+        let v = v * 2.0f64.powi(e / 2) * 2.0f64.powi(e - e / 2);
+        if self.neg { -v } else { v }
+    }
+
+    /// Number of bits after the point.
+    pub fn precision(&self) -> usize {
+        self.frac * 32
+    }
+
+    /// Changes the number of bits after the point, truncating if it shrinks.
+    pub fn set_precision(&mut self, bits: usize) {
+        let frac = limbs_for(bits);
+        if frac > self.frac {
+            let mut limbs = vec![0; frac - self.frac];
+            limbs.extend_from_slice(&self.limbs);
+            self.limbs = limbs;
+        } else {
+            self.limbs.drain(0..self.frac - frac);
+        }
+        self.frac = frac;
+        self.trim();
+    }
+
+    pub fn with_precision(mut self, bits: usize) -> BigFloat {
+        self.set_precision(bits);
+        self
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.iter().all(|&l| l == 0)
+    }
+
+    fn trim(&mut self) {
+        while self.limbs.len() > self.frac + 1 && *self.limbs.last().unwrap() == 0 {
+            self.limbs.pop();
+        }
+        if self.is_zero() { self.neg = false; }
+    }
+
+    /// Adds `v` to the magnitude, starting at limb `at`.
+    fn add_at(&mut self, at: usize, v: u64) {
+        let mut carry = v as u128;
+        let mut i = at;
+        while carry != 0 {
+            if i >= self.limbs.len() { self.limbs.push(0); }
+            let sum = self.limbs[i] as u128 + (carry & 0xffff_ffff);
+            self.limbs[i] = sum as u32;
+            carry = (carry >> 32) + (sum >> 32);
+            i += 1;
+        }
+    }
+
+    fn mul_small(&mut self, m: u32) {
+        let mut carry = 0u64;
+        for limb in self.limbs.iter_mut() {
+            let prod = *limb as u64 * m as u64 + carry;
+            *limb = prod as u32;
+            carry = prod >> 32;
+        }
+        if carry != 0 { self.limbs.push(carry as u32); }
+    }
+
+    /// Divides the magnitude by `d`, returning the remainder in units of the lowest limb.
+    fn div_small(&mut self, d: u32) -> u32 {
+        let mut rem = 0u64;
+        for limb in self.limbs.iter_mut().rev() {
+            let cur = (rem << 32) | *limb as u64;
+            *limb = (cur / d as u64) as u32;
+            rem = cur % d as u64;
+        }
+        rem as u32
+    }
+
+    fn aligned(&self, frac: usize) -> BigFloat {
+        let mut out = self.clone();
+        if frac > out.frac { out.set_precision(frac * 32); }
+        out
+    }
+
+    fn signed_add(a: &BigFloat, b: &BigFloat, b_neg: bool) -> BigFloat {
+        let frac = a.frac.max(b.frac);
+        let (a, b) = (a.aligned(frac), b.aligned(frac));
+        let mut out = if a.neg == b_neg {
+            BigFloat { neg: a.neg, limbs: add_mag(&a.limbs, &b.limbs), frac }
+        } else if cmp_mag(&a.limbs, &b.limbs) != Ordering::Less {
+            BigFloat { neg: a.neg, limbs: sub_mag(&a.limbs, &b.limbs), frac }
+        } else {
+            BigFloat { neg: b_neg, limbs: sub_mag(&b.limbs, &a.limbs), frac }
+        };
+        out.trim();
+        out
+    }
+}
+
+impl From<f64> for BigFloat {
+    /// Converts exactly, with however much precision that takes.
+    fn from(v: f64) -> BigFloat {
+        let exp = ((v.to_bits() >> 52) & 0x7ff) as i64;
+        // The lowest bit of the mantissa is worth 2^(exp - 1075)
+        BigFloat::from_f64(v, (1075 - exp.max(1)).max(64) as usize)
+    }
+}
+
+impl<'b> Add<&'b BigFloat> for &BigFloat {
+    type Output = BigFloat;
+    fn add(self, other: &'b BigFloat) -> BigFloat { BigFloat::signed_add(self, other, other.neg) }
+}
+
+impl<'b> Sub<&'b BigFloat> for &BigFloat {
+    type Output = BigFloat;
+    fn sub(self, other: &'b BigFloat) -> BigFloat { BigFloat::signed_add(self, other, !other.neg) }
+}
+
+impl<'b> Mul<&'b BigFloat> for &BigFloat {
+    type Output = BigFloat;
+    fn mul(self, other: &'b BigFloat) -> BigFloat {
+        let frac = self.frac.max(other.frac);
+        let (a, b) = (self.aligned(frac), other.aligned(frac));
+        let mut wide = vec![0u64; a.limbs.len() + b.limbs.len() + 1];
+        for (i, &x) in a.limbs.iter().enumerate() {
+            if x == 0 { continue; }
+            let mut carry = 0u64;
+            for (j, &y) in b.limbs.iter().enumerate() {
+                let cur = wide[i + j] + x as u64 * y as u64 + carry;
+                wide[i + j] = cur & 0xffff_ffff;
+                carry = cur >> 32;
+            }
+            let mut k = i + b.limbs.len();
+            while carry != 0 {
+                let cur = wide[k] + carry;
+                wide[k] = cur & 0xffff_ffff;
+                carry = cur >> 32;
+                k += 1;
+            }
+        }
+        // The product has 2*frac limbs after the point, drop the lowest frac
+        let limbs = wide[frac..].iter().map(|&l| l as u32).collect();
+        let mut out = BigFloat { neg: a.neg != b.neg, limbs, frac };
+        out.trim();
+        out
+    }
+}
+
+impl Neg for &BigFloat {
+    type Output = BigFloat;
+    fn neg(self) -> BigFloat {
+        let mut out = self.clone();
+        out.neg = !out.neg;
+        out.trim();
+        out
+    }
+}
+
+impl Add for BigFloat {
+    type Output = BigFloat;
+    fn add(self, other: BigFloat) -> BigFloat { &self + &other }
+}
+
+impl Sub for BigFloat {
+    type Output = BigFloat;
+    fn sub(self, other: BigFloat) -> BigFloat { &self - &other }
+}
+
+impl Mul for BigFloat {
+    type Output = BigFloat;
+    fn mul(self, other: BigFloat) -> BigFloat { &self * &other }
+}
+
+impl Neg for BigFloat {
+    type Output = BigFloat;
+    fn neg(self) -> BigFloat { -&self }
+}
+
+impl PartialEq for BigFloat {
+    fn eq(&self, other: &BigFloat) -> bool {
+        (self - other).is_zero()
+    }
+}
+
+impl PartialOrd for BigFloat {
+    fn partial_cmp(&self, other: &BigFloat) -> Option<Ordering> {
+        let diff = self - other;
+        Some(if diff.is_zero() { Ordering::Equal } else if diff.neg { Ordering::Less } else { Ordering::Greater })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseBigFloatError {
+    pub column: usize,
+}
+
+impl fmt::Display for ParseBigFloatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid number at column {}", self.column)
+    }
+}
+
+impl FromStr for BigFloat {
+    type Err = ParseBigFloatError;
+
+    /// Parses plain or scientific decimal notation. The precision is just
+    /// enough to hold every digit given. Exponents beyond ten thousand either
+    /// way are rejected.
+    fn from_str(s: &str) -> Result<BigFloat, ParseBigFloatError> {
+        let s = s.trim();
+        let neg = s.starts_with('-');
+        let start = if neg || s.starts_with('+') { 1 } else { 0 };
+
+        let mut digits = Vec::new();
+        let mut point = None;
+        let mut exp: i64 = 0;
+        for (i, c) in s.char_indices().skip(start) {
+            match c {
+                '0'..='9' => digits.push(c as u8 - b'0'),
+                '.' if point.is_none() => point = Some(digits.len()),
+                'e' | 'E' if !digits.is_empty() => {
+                    let rest = &s[i + 1..];
+                    exp = rest.parse().map_err(|_| ParseBigFloatError { column: i + 2 })?;
+                    if exp.unsigned_abs() > MAX_EXPONENT as u64 { return Err(ParseBigFloatError { column: i + 2 }) }
+                    break;
+                },
+                _ => return Err(ParseBigFloatError { column: i + 1 }),
+            }
+        }
+        if digits.is_empty() { return Err(ParseBigFloatError { column: s.len() + 1 }) }
+
+        // Move the decimal point so that digits[..point] is the integer part
+        let point = point.unwrap_or(digits.len()) as i64 + exp;
+        if point < 0 {
+            let mut padded = vec![0; (-point) as usize];
+            padded.extend_from_slice(&digits);
+            digits = padded;
+        }
+        let point = point.max(0) as usize;
+        if point > digits.len() { digits.resize(point, 0); }
+
+        let frac_digits = digits.len() - point;
+        let mut frac = 2;
+        while digits_for(frac) < frac_digits { frac += 1; }
+
+        // One guard limb so the result can be rounded rather than truncated
+        let mut out = BigFloat { neg: false, limbs: vec![0; frac + 1], frac: frac + 1 };
+        for &d in digits[point..].iter().rev() {
+            out.add_at(frac + 1, d as u64);
+            out.div_small(10);
+        }
+        let mut int = BigFloat { neg: false, limbs: vec![0; frac + 1], frac: frac + 1 };
+        for &d in &digits[..point] {
+            int.mul_small(10);
+            int.add_at(frac + 1, d as u64);
+        }
+        out.limbs = add_mag(&out.limbs, &int.limbs);
+
+        let round = out.limbs[0] >= 0x8000_0000;
+        out.limbs.remove(0);
+        out.frac = frac;
+        if round { out.add_at(0, 1); }
+        out.neg = neg;
+        out.trim();
+        Ok(out)
+    }
+}
+
+impl fmt::Display for BigFloat {
+    /// Prints the exact value if it is short, otherwise exactly enough digits
+    /// for the value to parse back unchanged.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut int = BigFloat { neg: false, limbs: self.limbs[self.frac..].to_vec(), frac: 0 };
+        let mut frac = BigFloat { neg: false, limbs: self.limbs[..self.frac].to_vec(), frac: self.frac };
+
+        let mut int_digits = Vec::new();
+        while !int.is_zero() {
+            int_digits.push((b'0' + int.div_small(10) as u8) as char);
+        }
+        if int_digits.is_empty() { int_digits.push('0'); }
+        int_digits.reverse();
+
+        let mut frac_digits = String::new();
+        for _ in 0..digits_for(self.frac) {
+            if frac.is_zero() { break; }
+            frac.mul_small(10);
+            let digit = frac.limbs.get(self.frac).cloned().unwrap_or(0);
+            frac.limbs.truncate(self.frac);
+            frac_digits.push((b'0' + digit as u8) as char);
+        }
+
+        if self.neg { write!(f, "-")?; }
+        for c in int_digits { write!(f, "{}", c)?; }
+        if !frac_digits.is_empty() { write!(f, ".{}", frac_digits)?; }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BigFloat, ParseBigFloatError};
+
+    #[test]
+    fn huge_exponents_are_rejected() {
+        assert_eq!("1e999999999".parse::<BigFloat>().unwrap_err(), ParseBigFloatError { column: 3 });
+        assert_eq!("-2.5e-10001".parse::<BigFloat>().unwrap_err(), ParseBigFloatError { column: 6 });
+        assert_eq!("1e99999999999999999999".parse::<BigFloat>().unwrap_err(), ParseBigFloatError { column: 3 });
+        assert_eq!("1e-9223372036854775808".parse::<BigFloat>().unwrap_err(), ParseBigFloatError { column: 3 });
+    }
+
+    #[test]
+    fn exponents_up_to_the_limit_parse() {
+        assert!("1e-10000".parse::<BigFloat>().is_ok());
+        assert_eq!("1e10000".parse::<BigFloat>().unwrap().to_f64(), f64::INFINITY);
+        assert_eq!("-2.5e-3".parse::<BigFloat>().unwrap().to_f64(), -0.0025);
+    }
+}
@@ -1,55 +1,336 @@
-#[derive(Clone, Copy)]
+use std::fmt;
+use std::str::FromStr;
+use super::{BigFloat, ParseBigFloatError, Real};
+
+/// Describes the view being rendered.
+///
+/// The center is kept at arbitrary precision, and pixel coordinates are
+/// worked out as small offsets from it, so a location survives deep zooms
+/// and being written out and read back in without drifting. Contexts can be
+/// saved with `to_string` and loaded with `parse`. `precision` picks the
+/// number type points are computed and iterated in, and `strategy` which
+/// of them are worked out at all.
+///
+/// ```
+/// # extern crate fractal;
+/// # use fractal::RenderingContext;
+/// # fn main() {
+/// let ctx = RenderingContext::from_center("-0.743643887037158704752191506114774", "0.131825904205311970493132056385139",
+///                                         1e-30, 5000, 640, 480).unwrap();
+/// let loaded : RenderingContext = ctx.to_string().parse().unwrap();
+/// assert_eq!(loaded.x, ctx.x);
+/// assert_eq!(loaded.y, ctx.y);
+/// assert_eq!(loaded.scale, ctx.scale);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
 pub struct RenderingContext {
-    pub x: f64,
-    pub y: f64,
+    pub x: BigFloat,
+    pub y: BigFloat,
     pub scale: f64,
     pub max_iter: u64,
     pub x_px: u32,
     pub y_px: u32,
+    pub precision: Precision,
+    pub strategy: Strategy,
+}
+
+/// The number type a render is done in.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Precision {
+    /// Fastest, good down to a scale of about `1e-13`
+    #[default]
+    F64,
+    /// `DoubleDouble`, good down to about `1e-30`
+    DoubleDouble,
+    /// `BigFloat` at whatever precision the scale needs
+    BigFloat,
+}
+
+/// How a render picks the pixels it works out.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Strategy {
+    /// Every pixel on its own
+    #[default]
+    EveryPixel,
+    /// Mariani-Silver subdivision. Only the border of a rectangle is worked
+    /// out, and if every pixel on it came out the same the inside is filled
+    /// with that, otherwise the rectangle is split in two and each half is
+    /// tried again. A rectangle is only filled if its middle row and column
+    /// came out the same as well. Pixels count as the same when they'd be
+    /// colored the same, so smooth colorings only fill the inside of the set
+    /// while formulas that only count iterations fill whole bands. Views with
+    /// lots of the inside of the set showing come out several times faster.
+    /// Filaments thinner than a pixel can still slip through though, so up to
+    /// one pixel in a thousand may differ from `EveryPixel`; the tests hold
+    /// minibrots, seahorse valley under `Coloring::Distance` and Julia
+    /// iteration counts to that. Used by `render_image`,
+    /// `render_image_rgb` and `render_samples`, other renders work out every
+    /// pixel.
+    ///
+    /// ```
+    /// # extern crate fractal;
+    /// # use fractal::{render_samples, ColorScheme, Fractal, Monitor, Strategy};
+    /// # use fractal::formulas::Mandelbrot;
+    /// # fn main() {
+    /// let mut cs = ColorScheme::new();
+    /// cs.add_hex(0xffffff, 0.0);
+    /// let mut ctx = Mandelbrot.default_context();
+    /// ctx.x_px = 150;
+    /// ctx.y_px = 100;
+    /// let every = render_samples(ctx.clone(), &cs, Mandelbrot, &Monitor::new()).unwrap();
+    /// ctx.strategy = Strategy::Subdivide;
+    /// let subdivided = render_samples(ctx.clone(), &cs, Mandelbrot, &Monitor::new()).unwrap();
+    /// assert!(every == subdivided);
+    ///
+    /// // Plain iteration counts, which come in bands
+    /// let count = |x0: f64, y0: f64, max_iter: u64| Mandelbrot.iterate(x0, y0, max_iter, 0.0);
+    /// let subdivided = render_samples(ctx.clone(), &cs, count, &Monitor::new()).unwrap();
+    /// ctx.strategy = Strategy::EveryPixel;
+    /// assert!(render_samples(ctx, &cs, count, &Monitor::new()).unwrap() == subdivided);
+    /// # }
+    /// ```
+    Subdivide,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Strategy::EveryPixel => write!(f, "every-pixel"),
+            Strategy::Subdivide => write!(f, "subdivide"),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Precision::F64 => write!(f, "f64"),
+            Precision::DoubleDouble => write!(f, "double-double"),
+            Precision::BigFloat => write!(f, "big-float"),
+        }
+    }
+}
+
+/// A center coordinate split into the nearest `T` and what that leaves out.
+#[derive(Clone)]
+struct Center<T> {
+    hi: T,
+    lo: f64,
+}
+
+impl<T: Real> Center<T> {
+    fn new(v: &BigFloat, bits: usize) -> Center<T> {
+        let hi = T::from_big(&v.clone().with_precision(bits.max(v.precision())));
+        let lo = (v - &hi.to_big()).to_f64();
+        Center { hi, lo }
+    }
+
+    /// The `T` nearest to the center plus `offset`.
+    fn add(&self, offset: f64) -> T {
+        self.hi.clone() + T::from_f64(offset + self.lo)
+    }
 }
 
 impl RenderingContext {
+    /// Creates a context centered on coordinates given as decimal strings.
+    pub fn from_center(x: &str, y: &str, scale: f64, max_iter: u64, x_px: u32, y_px: u32) -> Result<RenderingContext, ParseBigFloatError> {
+        Ok(RenderingContext {
+            x: x.parse()?, y: y.parse()?,
+            scale, max_iter,
+            x_px, y_px,
+            precision: Precision::F64,
+            strategy: Strategy::EveryPixel,
+        })
+    }
+
+    /// Checks the view is a positive number wide. Every render of a view in
+    /// the complex plane does this before starting.
+    ///
+    /// ```
+    /// # extern crate fractal;
+    /// # use fractal::{Fractal, Error};
+    /// # use fractal::formulas::Mandelbrot;
+    /// # fn main() {
+    /// let mut ctx = Mandelbrot.default_context();
+    /// assert!(ctx.validate().is_ok());
+    /// ctx.scale = 0.0;
+    /// assert_eq!(ctx.validate().unwrap_err().to_string(), "scale 0 isn't a positive number");
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.scale.is_finite() && self.scale > 0.0 { Ok(()) } else { Err(Error::Scale(self.scale)) }
+    }
+
+    /// Bits after the point needed to tell neighbouring pixels apart, with
+    /// some to spare for the error iterating builds up.
+    pub fn bits(&self) -> usize {
+        (64.0 - (self.scale/self.x_px.max(self.y_px) as f64).log2()).max(64.0) as usize
+    }
+
+    fn y_scale(&self) -> f64 {
+        self.scale * ((self.y_px as f64)/(self.x_px as f64))
+    }
+
+    /// Width of a pixel in the complex plane.
+    pub fn pixel_size(&self) -> f64 {
+        self.scale / self.x_px as f64
+    }
+
+    /// Offset of a pixel from the center.
+    pub fn pixel_offset(&self, x_px: u32, y_px: u32) -> (f64, f64) {
+        let y_scale = self.y_scale();
+        (self.scale*(x_px as f64/self.x_px as f64) - self.scale/2.0,
+         y_scale*(y_px as f64/self.y_px as f64) - y_scale/2.0)
+    }
+
     pub fn enumerate_points(&self) -> ImageIterator {
-        let x_scale = self.scale;
-        let y_scale = self.scale * ((self.y_px as f64)/(self.x_px as f64));
-        let x_offset = self.x as f64 - x_scale/2.0;
-        let y_offset = self.y as f64 - y_scale/2.0;
+        self.enumerate_points_as()
+    }
+
+    pub fn enumerate_rows(&self) -> RowIterator {
+        self.enumerate_rows_as()
+    }
+
+    /// `enumerate_points` with the coordinates computed as `T`.
+    pub fn enumerate_points_as<T: Real>(&self) -> ImageIterator<T> {
         ImageIterator{
-            x_scale, y_scale,
-            x_offset, y_offset,
+            x_scale: self.scale, y_scale: self.y_scale(),
+            x_center: Center::new(&self.x, self.bits()), y_center: Center::new(&self.y, self.bits()),
             cur_x: 0, cur_y: 0,
             x_px: self.x_px, y_px: self.y_px,
         }
     }
 
-    pub fn enumerate_rows(&self) -> RowIterator {
-        let x_scale = self.scale;
-        let y_scale = self.scale * ((self.y_px as f64)/(self.x_px as f64));
-        let x_offset = self.x as f64 - x_scale/2.0;
-        let y_offset = self.y as f64 - y_scale/2.0;
+    /// `enumerate_rows` with the coordinates computed as `T`.
+    pub fn enumerate_rows_as<T: Real>(&self) -> RowIterator<T> {
+        self.enumerate_tile_as(0, 0, self.x_px, self.y_px)
+    }
+
+    /// The rows of the `width` by `height` block of pixels with its top left
+    /// corner at `x`, `y`, with the coordinates computed as `T`.
+    pub fn enumerate_tile_as<T: Real>(&self, x: u32, y: u32, width: u32, height: u32) -> RowIterator<T> {
         RowIterator{
-            x_scale, y_scale,
-            x_offset, y_offset,
-            cur_y: 0,
+            x_scale: self.scale, y_scale: self.y_scale(),
+            x_center: Center::new(&self.x, self.bits()), y_center: Center::new(&self.y, self.bits()),
+            x_start: x, x_end: (x + width).min(self.x_px),
+            y_start: y, y_end: (y + height).min(self.y_px),
+            cur_y: y,
+            x_px: self.x_px, y_px: self.y_px,
+        }
+    }
+
+    /// Coordinates of any pixel as `T`, for renders that don't visit the
+    /// pixels in order.
+    pub(crate) fn points_as<T: Real>(&self) -> Points<T> {
+        Points {
+            x_scale: self.scale, y_scale: self.y_scale(),
+            x_center: Center::new(&self.x, self.bits()), y_center: Center::new(&self.y, self.bits()),
             x_px: self.x_px, y_px: self.y_px,
         }
     }
 }
 
-pub struct ImageIterator {
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseContextError {
+    /// A line that isn't `key = value`, or whose value doesn't parse. A
+    /// scale also has to be a positive number.
+    Syntax(usize),
+    /// A field that was never given
+    Missing(&'static str),
+}
+
+impl fmt::Display for ParseContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseContextError::Syntax(line) => write!(f, "invalid line {}", line),
+            ParseContextError::Missing(key) => write!(f, "missing {}", key),
+        }
+    }
+}
+
+impl fmt::Display for RenderingContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "x = {}", self.x)?;
+        writeln!(f, "y = {}", self.y)?;
+        // Debug formatting of floats is the shortest string that reads back exactly
+        writeln!(f, "scale = {:?}", self.scale)?;
+        writeln!(f, "max_iter = {}", self.max_iter)?;
+        writeln!(f, "x_px = {}", self.x_px)?;
+        writeln!(f, "y_px = {}", self.y_px)?;
+        writeln!(f, "precision = {}", self.precision)?;
+        writeln!(f, "strategy = {}", self.strategy)
+    }
+}
+
+impl FromStr for RenderingContext {
+    type Err = ParseContextError;
+
+    /// Reads the `key = value` lines written by `Display`. Blank lines and
+    /// lines starting with `#` are ignored. `precision` defaults to `f64` and
+    /// `strategy` to `every-pixel`.
+    fn from_str(s: &str) -> Result<RenderingContext, ParseContextError> {
+        let (mut x, mut y, mut scale, mut max_iter, mut x_px, mut y_px) = (None, None, None, None, None, None);
+        let mut precision = Precision::F64;
+        let mut strategy = Strategy::EveryPixel;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let err = ParseContextError::Syntax(i + 1);
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = parts.next().ok_or(err.clone())?.trim();
+            match key {
+                "x" => x = Some(value.parse().map_err(|_| err)?),
+                "y" => y = Some(value.parse().map_err(|_| err)?),
+                "scale" => scale = match value.parse::<f64>() {
+                    Ok(scale) if scale.is_finite() && scale > 0.0 => Some(scale),
+                    _ => return Err(err),
+                },
+                "max_iter" => max_iter = Some(value.parse().map_err(|_| err)?),
+                "x_px" => x_px = Some(value.parse().map_err(|_| err)?),
+                "y_px" => y_px = Some(value.parse().map_err(|_| err)?),
+                "precision" => precision = match value {
+                    "f64" => Precision::F64,
+                    "double-double" => Precision::DoubleDouble,
+                    "big-float" => Precision::BigFloat,
+                    _ => return Err(err),
+                },
+                "strategy" => strategy = match value {
+                    "every-pixel" => Strategy::EveryPixel,
+                    "subdivide" => Strategy::Subdivide,
+                    _ => return Err(err),
+                },
+                _ => return Err(err),
+            }
+        }
+        Ok(RenderingContext {
+            x: x.ok_or(ParseContextError::Missing("x"))?,
+            y: y.ok_or(ParseContextError::Missing("y"))?,
+            scale: scale.ok_or(ParseContextError::Missing("scale"))?,
+            max_iter: max_iter.ok_or(ParseContextError::Missing("max_iter"))?,
+            x_px: x_px.ok_or(ParseContextError::Missing("x_px"))?,
+            y_px: y_px.ok_or(ParseContextError::Missing("y_px"))?,
+            precision,
+            strategy,
+        })
+    }
+}
+
+pub struct ImageIterator<T = f64> {
     x_scale: f64,
     y_scale: f64,
-    x_offset: f64,
-    y_offset: f64,
+    x_center: Center<T>,
+    y_center: Center<T>,
     cur_x: u32,
     cur_y: u32,
     x_px: u32,
     y_px: u32,
 }
 
-impl Iterator for ImageIterator {
-    type Item = (f64, f64, u32, u32);
-    fn next(&mut self) -> Option<(f64, f64, u32, u32)> {
+impl<T: Real> Iterator for ImageIterator<T> {
+    type Item = (T, T, u32, u32);
+    fn next(&mut self) -> Option<(T, T, u32, u32)> {
         if self.cur_x >= self.x_px { 
             self.cur_x = 0;
             self.cur_y += 1;
@@ -59,63 +340,88 @@ impl Iterator for ImageIterator {
             }
         }
 
-        let ret = Some((self.x_scale*(self.cur_x as f64/self.x_px as f64) + self.x_offset,
-              self.y_scale*(self.cur_y as f64/self.y_px as f64) + self.y_offset,
+        let ret = Some((self.x_center.add(self.x_scale*(self.cur_x as f64/self.x_px as f64) - self.x_scale/2.0),
+              self.y_center.add(self.y_scale*(self.cur_y as f64/self.y_px as f64) - self.y_scale/2.0),
               self.cur_x, self.cur_y));
         self.cur_x += 1;
         ret
     }
 }
 
-pub struct RowIterator {
+pub(crate) struct Points<T> {
+    x_scale: f64,
+    y_scale: f64,
+    x_center: Center<T>,
+    y_center: Center<T>,
+    x_px: u32,
+    y_px: u32,
+}
+
+impl<T: Real> Points<T> {
+    /// The same coordinates the iterators give for the pixel.
+    pub(crate) fn at(&self, x_px: u32, y_px: u32) -> (T, T) {
+        (self.x_center.add(self.x_scale*(x_px as f64/self.x_px as f64) - self.x_scale/2.0),
+         self.y_center.add(self.y_scale*(y_px as f64/self.y_px as f64) - self.y_scale/2.0))
+    }
+}
+
+pub struct RowIterator<T = f64> {
     x_scale: f64,
     y_scale: f64,
-    x_offset: f64,
-    y_offset: f64,
+    x_center: Center<T>,
+    y_center: Center<T>,
+    x_start: u32,
+    x_end: u32,
+    y_start: u32,
+    y_end: u32,
     cur_y: u32,
     x_px: u32,
     y_px: u32,
 }
 
-impl Iterator for RowIterator {
-    type Item = (RowPixelIterator, u32);
-    fn next(&mut self) -> Option<(RowPixelIterator, u32)> {
-        if self.cur_y >= self.y_px {
-            self.cur_y = 0;
+impl<T: Real> Iterator for RowIterator<T> {
+    type Item = (RowPixelIterator<T>, u32);
+    fn next(&mut self) -> Option<(RowPixelIterator<T>, u32)> {
+        if self.cur_y >= self.y_end {
+            self.cur_y = self.y_start;
             return None
         }
 
         let ret = Some((RowPixelIterator{ 
             x_scale: self.x_scale,
-            x_offset: self.x_offset,
-            cur_x: 0,
+            x_center: self.x_center.clone(),
+            x_start: self.x_start,
+            x_end: self.x_end,
+            cur_x: self.x_start,
             x_px: self.x_px,
-            y_offset: self.y_scale*(self.cur_y as f64/self.y_px as f64) + self.y_offset,
+            y: self.y_center.add(self.y_scale*(self.cur_y as f64/self.y_px as f64) - self.y_scale/2.0),
         }, self.cur_y));
         self.cur_y += 1;
         ret
     }
 }
 
-pub struct RowPixelIterator {
+pub struct RowPixelIterator<T = f64> {
     x_scale: f64,
-    x_offset: f64,
+    x_center: Center<T>,
+    x_start: u32,
+    x_end: u32,
     cur_x: u32,
     x_px: u32,
-    y_offset: f64,
+    y: T,
 }
 
-impl Iterator for RowPixelIterator {
-    type Item = (f64, f64, u32);
-    fn next(&mut self) -> Option<(f64, f64, u32)> {
-        if self.cur_x >= self.x_px {
-            self.cur_x = 0;
+impl<T: Real> Iterator for RowPixelIterator<T> {
+    type Item = (T, T, u32);
+    fn next(&mut self) -> Option<(T, T, u32)> {
+        if self.cur_x >= self.x_end {
+            self.cur_x = self.x_start;
             return None
         }
 
         let ret = Some((
-                self.x_scale*(self.cur_x as f64/self.x_px as f64) + self.x_offset,
-                self.y_offset, self.cur_x
+                self.x_center.add(self.x_scale*(self.cur_x as f64/self.x_px as f64) - self.x_scale/2.0),
+                self.y.clone(), self.cur_x
             ));
         self.cur_x += 1;
         ret
@@ -123,6 +429,7 @@ impl Iterator for RowPixelIterator {
 }
 
 use image::Rgb;
+use super::{Coloring, Interior, Error};
 
 pub struct ColorSchemeColor {
     color: Rgb<u8>,
@@ -138,13 +445,46 @@ impl ColorSchemeColor {
     }
 }
 
+#[derive(Default)]
 pub struct ColorScheme {
     colors: Vec<ColorSchemeColor>,
+    coloring: Coloring,
+    interior: Interior,
+    interior_colors: Option<Box<ColorScheme>>,
 }
 
 impl ColorScheme {
     pub fn new() -> ColorScheme {
-        ColorScheme { colors: Vec::new() }
+        ColorScheme { colors: Vec::new(), coloring: Coloring::Histogram, interior: Interior::default(), interior_colors: None }
+    }
+
+    pub fn set_coloring(&mut self, coloring: Coloring) {
+        self.coloring = coloring;
+    }
+
+    pub fn coloring(&self) -> Coloring {
+        self.coloring
+    }
+
+    pub fn set_interior(&mut self, interior: Interior) {
+        self.interior = interior;
+    }
+
+    pub fn interior(&self) -> Interior {
+        self.interior
+    }
+
+    /// Colors points inside the set from `colors` instead of this scheme.
+    pub fn set_interior_colors(&mut self, colors: ColorScheme) {
+        self.interior_colors = Some(Box::new(colors));
+    }
+
+    /// The color of a point inside the set with the given `Interior::value`.
+    pub fn interior_color(&self, value: f64) -> Rgb<u8> {
+        match self.interior {
+            Interior::Solid(color) => color,
+            _ => self.interior_colors.as_ref().map_or(self, |cs| cs).get_color(value),
+        }
     }
 
     fn add_color(&mut self, color: ColorSchemeColor) {
@@ -168,11 +508,75 @@ impl ColorScheme {
         Rgb([r,g,b])
     }
 
+    /// The color at `pos`, blended from the colors either side of it.
+    /// Anywhere before the first color or after the last gets that color, and
+    /// a scheme without any colors is black all over.
     pub fn get_color(&self, pos: f64) -> Rgb<u8> {
         let mut i = 0;
         while i < self.colors.len() && self.colors[i].position < pos { i += 1; }
+        if i == 0 { return self.colors.first().map_or(Rgb([0, 0, 0]), |c| c.color) }
+        if i == self.colors.len() { return self.colors[i-1].color }
         let a = &self.colors[i];
-        let b = if i == 0 { &self.colors[0] } else { &self.colors[i-1] };
-        ColorScheme::lerp(&a.color, &b.color, (pos - a.position) as f64/(b.position - a.position) as f64)
+        let b = &self.colors[i-1];
+        ColorScheme::lerp(&a.color, &b.color, (pos - a.position)/(b.position - a.position))
+    }
+
+    /// Checks this scheme and its interior scheme have colors to give out.
+    /// Every render does this before starting.
+    ///
+    /// ```
+    /// # extern crate fractal;
+    /// # use fractal::ColorScheme;
+    /// # fn main() {
+    /// let mut cs = ColorScheme::new();
+    /// assert_eq!(cs.validate().unwrap_err().to_string(), "color scheme has no colors");
+    /// cs.add_hex(0xffffff, 0.0);
+    /// assert!(cs.validate().is_ok());
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.colors.is_empty() { return Err(Error::EmptyColorScheme) }
+        if let Some(color) = self.colors.iter().find(|c| !c.position.is_finite()) {
+            return Err(Error::ColorPosition(color.position))
+        }
+        match self.interior_colors {
+            Some(ref colors) => colors.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RenderingContext, ParseContextError};
+    use super::super::{render_image_rgb, ColorScheme, Error, Fractal, Monitor};
+    use super::super::formulas::Mandelbrot;
+
+    #[test]
+    fn contexts_round_trip() {
+        let mut ctx = Mandelbrot.default_context();
+        ctx.scale = 1.5e-7;
+        let loaded : RenderingContext = ctx.to_string().parse().unwrap();
+        assert_eq!(loaded.to_string(), ctx.to_string());
+    }
+
+    #[test]
+    fn scales_have_to_be_positive_numbers() {
+        let valid = Mandelbrot.default_context().to_string();
+        let line = valid.lines().position(|line| line.starts_with("scale")).unwrap() + 1;
+        for &scale in &["0", "-1.5", "NaN", "inf", "-0"] {
+            let text = valid.replace(&format!("scale = {:?}", Mandelbrot.default_context().scale), &format!("scale = {}", scale));
+            assert_eq!(text.parse::<RenderingContext>().unwrap_err(), ParseContextError::Syntax(line));
+        }
+
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0xffffff, 0.0);
+        for &scale in &[0.0, -2.0, f64::NAN, f64::INFINITY] {
+            let mut ctx = Mandelbrot.default_context();
+            ctx.scale = scale;
+            ctx.x_px = 8;
+            ctx.y_px = 8;
+            assert!(matches!(render_image_rgb(ctx, &cs, Mandelbrot, &Monitor::new()), Err(Error::Scale(_))));
+        }
     }
 }
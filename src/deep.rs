@@ -0,0 +1,320 @@
+use std::path::Path;
+use std::thread;
+use std::sync::{Arc, mpsc};
+use super::{RenderingContext, ColorScheme, EscapeResult, BigFloat, Error, Monitor, Phase};
+use super::error::join;
+use super::fractal::{normalized, SMOOTH_BAILOUT};
+use super::util::{colorize, Sample};
+use num_cpus;
+use spmc;
+use image;
+
+/// A pixel is considered glitched once `|z|^2` drops below this fraction of
+/// `|Z|^2`, at which point the reference orbit no longer describes it.
+const GLITCH_TOLERANCE: f64 = 1e-6;
+
+/// How many times a new reference is picked before giving up on glitches.
+const MAX_REFERENCES: usize = 32;
+
+/// How many pixels are sent to a worker at once.
+const CHUNK: usize = 256;
+
+/// Number of terms in the series approximation.
+const SERIES_TERMS: usize = 4;
+
+/// Largest error relative to `|dz|` the series approximation may have at the
+/// probe points before it is considered invalid.
+const SERIES_TOLERANCE: f64 = 1e-12;
+
+fn mul(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0*b.0 - a.1*b.1, a.0*b.1 + a.1*b.0)
+}
+
+fn norm(a: (f64, f64)) -> f64 {
+    a.0*a.0 + a.1*a.1
+}
+
+/// Polynomial in `dc` approximating `dz` after `skip` iterations, for every
+/// pixel near the reference at once.
+struct Series {
+    skip: u64,
+    /// Coefficients of `dc`, `dc^2`, ... `dc^SERIES_TERMS`
+    coeffs: [(f64, f64); SERIES_TERMS],
+}
+
+impl Series {
+    fn evaluate(&self, dc: (f64, f64)) -> (f64, f64) {
+        let mut power = dc;
+        let mut sum = (0.0, 0.0);
+        for coeff in &self.coeffs {
+            let term = mul(*coeff, power);
+            sum = (sum.0 + term.0, sum.1 + term.1);
+            power = mul(power, dc);
+        }
+        sum
+    }
+
+    /// Fits the series to the reference orbit, advancing it for as long as
+    /// it agrees with perturbation at every probe point.
+    fn fit(orbit: &[(f64, f64)], probes: &[(f64, f64)], max_iter: u64) -> Series {
+        let mut series = Series { skip: 0, coeffs: [(0.0, 0.0); SERIES_TERMS] };
+        let mut exact : Vec<(f64, f64)> = vec![(0.0, 0.0); probes.len()];
+
+        while series.skip + 1 < max_iter && (series.skip as usize) + 2 < orbit.len() {
+            let z = orbit[series.skip as usize];
+            let two_z = (2.0*z.0, 2.0*z.1);
+
+            // dz' = 2*Z*dz + dz^2 + dc, collected by powers of dc
+            let mut next = [(0.0, 0.0); SERIES_TERMS];
+            for (k, term) in next.iter_mut().enumerate() {
+                *term = mul(two_z, series.coeffs[k]);
+                for i in 0..k {
+                    let square = mul(series.coeffs[i], series.coeffs[k - 1 - i]);
+                    *term = (term.0 + square.0, term.1 + square.1);
+                }
+            }
+            next[0].0 += 1.0;
+
+            let candidate = Series { skip: series.skip + 1, coeffs: next };
+            let z = orbit[candidate.skip as usize];
+            let mut valid = true;
+            for (dz, &dc) in exact.iter_mut().zip(probes) {
+                let d = *dz;
+                let prev = orbit[series.skip as usize];
+                *dz = (2.0*(prev.0*d.0 - prev.1*d.1) + d.0*d.0 - d.1*d.1 + dc.0,
+                       2.0*(prev.0*d.1 + prev.1*d.0) + 2.0*d.0*d.1 + dc.1);
+
+                let approx = candidate.evaluate(dc);
+                let error = norm((approx.0 - dz.0, approx.1 - dz.1));
+                // Stop before any probe escapes, past that the series is meaningless
+                if error > SERIES_TOLERANCE*SERIES_TOLERANCE*norm(*dz) || norm((z.0 + dz.0, z.1 + dz.1)) > 4.0 || !error.is_finite() {
+                    valid = false;
+                }
+            }
+            if !valid { break; }
+            series = candidate;
+        }
+
+        series
+    }
+}
+
+/// Iterates the Mandelbrot set at `(x, y)` with full precision, returning
+/// every `Z` of the orbit rounded to `f64`. Stops early if the point escapes.
+fn reference_orbit(x: &BigFloat, y: &BigFloat, max_iter: u64) -> Vec<(f64, f64)> {
+    let mut orbit = Vec::with_capacity(max_iter as usize + 1);
+    let mut zx = BigFloat::zero(x.precision());
+    let mut zy = BigFloat::zero(y.precision());
+    orbit.push((0.0, 0.0));
+
+    for _ in 0..max_iter {
+        let x2 = &zx * &zx;
+        let y2 = &zy * &zy;
+        let xy = &zx * &zy;
+        zx = &(&x2 - &y2) + x;
+        zy = &(&xy + &xy) + y;
+
+        let z = (zx.to_f64(), zy.to_f64());
+        orbit.push(z);
+        if z.0*z.0 + z.1*z.1 > SMOOTH_BAILOUT { break; }
+    }
+
+    orbit
+}
+
+/// Iterates `c = C + dc` relative to the reference orbit of `C`.
+///
+/// With `z = Z + dz` the Mandelbrot iteration becomes
+/// `dz' = 2*Z*dz + dz^2 + dc`, where every term is small enough for `f64`.
+/// The first `series.skip` iterations are taken from the series
+/// approximation instead. Returns `Err` with how badly the pixel glitched if
+/// the reference can't be trusted for it.
+fn perturb(orbit: &[(f64, f64)], series: &Series, dcx: f64, dcy: f64, max_iter: u64) -> Result<EscapeResult, f64> {
+    let (mut dx, mut dy) = series.evaluate((dcx, dcy));
+    let mut iter = series.skip;
+
+    while iter < max_iter {
+        // The reference escaped before this pixel did
+        if iter as usize + 1 >= orbit.len() { return Err(1.0) }
+
+        let (zx, zy) = orbit[iter as usize];
+        let xtemp = 2.0*(zx*dx - zy*dy) + dx*dx - dy*dy + dcx;
+        dy = 2.0*(zx*dy + zy*dx) + 2.0*dx*dy + dcy;
+        dx = xtemp;
+        iter += 1;
+
+        let (zx, zy) = orbit[iter as usize];
+        let x = zx + dx;
+        let y = zy + dy;
+        let norm = x*x + y*y;
+        if norm > SMOOTH_BAILOUT {
+            return Ok(EscapeResult::escaped(iter, normalized(iter, norm, SMOOTH_BAILOUT, 2.0, max_iter), (x, y)));
+        }
+
+        let ref_norm = zx*zx + zy*zy;
+        if norm < GLITCH_TOLERANCE * ref_norm { return Err(norm / ref_norm) }
+    }
+
+    let (zx, zy) = orbit[iter as usize];
+    Ok(EscapeResult::interior(max_iter, (zx + dx, zy + dy)))
+}
+
+/// Renders a Mandelbrot zoom too deep for `f64` coordinates.
+///
+/// The center is taken from `ctx.x` and `ctx.y` at whatever precision they
+/// were created with. One reference orbit is
+/// computed at full precision and every pixel is iterated as an `f64` offset
+/// from it. Pixels where the offset stops being accurate are detected and
+/// rendered again against a new reference placed among them. A series
+/// approximation lets every pixel skip the early iterations, which all pixels
+/// near the reference spend doing nearly the same thing. `ctx.scale` can go as
+/// low as about `1e-300`.
+pub fn render_deep_zoom(ctx: RenderingContext, cs: &ColorScheme, path: &Path, monitor: &Monitor) -> Result<(), Error> {
+    image::ImageRgb8(render_deep_zoom_rgb(ctx, cs, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_deep_zoom` without the file, handing back the picture instead.
+pub fn render_deep_zoom_rgb(ctx: RenderingContext, cs: &ColorScheme, monitor: &Monitor) -> Result<image::RgbImage, Error> {
+    ctx.validate()?;
+    cs.validate()?;
+    let coloring = cs.coloring();
+    let interior = cs.interior();
+    let bits = ctx.bits().max(ctx.x.precision()).max(ctx.y.precision());
+    let x = ctx.x.clone().with_precision(bits);
+    let y = ctx.y.clone().with_precision(bits);
+
+    // Offsets of every pixel from the center, which are small enough to be
+    // exact in f64
+    let mut offsets = Vec::with_capacity(ctx.x_px as usize*ctx.y_px as usize);
+    for y_px in 0..ctx.y_px {
+        for x_px in 0..ctx.x_px {
+            offsets.push(ctx.pixel_offset(x_px, y_px));
+        }
+    }
+    let mut results : Vec<Option<EscapeResult>> = vec![None; offsets.len()];
+    let mut pending : Vec<usize> = (0..offsets.len()).collect();
+    let mut reference = (0.0, 0.0);
+
+    let stage = monitor.phase(Phase::Rendering, offsets.len() as u64);
+
+    for pass in 0..MAX_REFERENCES {
+        let ref_x = &x + &BigFloat::from_f64(reference.0, bits);
+        let ref_y = &y + &BigFloat::from_f64(reference.1, bits);
+        let orbit = Arc::new(reference_orbit(&ref_x, &ref_y, ctx.max_iter));
+
+        // The corners of the area still to be rendered are where the series
+        // approximation is least accurate, so it is checked against them
+        let (mut min, mut max) = ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY));
+        for &i in &pending {
+            let (dcx, dcy) = (offsets[i].0 - reference.0, offsets[i].1 - reference.1);
+            min = (min.0.min(dcx), min.1.min(dcy));
+            max = (max.0.max(dcx), max.1.max(dcy));
+        }
+        let probes = [min, max, (min.0, max.1), (max.0, min.1)];
+        let series = Arc::new(Series::fit(&orbit, &probes, ctx.max_iter));
+        let last_pass = pass + 1 == MAX_REFERENCES;
+
+        let threads = num_cpus::get();
+        let mut handles = Vec::with_capacity(threads);
+        let (tx, rx) = spmc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..threads {
+            let rx = rx.clone();
+            let done_tx = done_tx.clone();
+            let orbit = orbit.clone();
+            let series = series.clone();
+            let max_iter = ctx.max_iter;
+            let monitor = monitor.clone();
+            handles.push(thread::spawn(move || {
+                while let Some(chunk) = rx.recv()? {
+                    monitor.check()?;
+                    let chunk : Vec<(usize, f64, f64)> = chunk;
+                    let out : Vec<(usize, Result<EscapeResult, f64>)> = chunk.into_iter()
+                        .map(|(i, dcx, dcy)| (i, perturb(&orbit, &series, dcx, dcy, max_iter)))
+                        .collect();
+                    // Nobody is left to collect the results
+                    if done_tx.send(out).is_err() { break }
+                }
+                Ok(())
+            }));
+        }
+        drop(done_tx);
+
+        for chunk in pending.chunks(CHUNK) {
+            let chunk : Vec<(usize, f64, f64)> = chunk.iter()
+                .map(|&i| (i, offsets[i].0 - reference.0, offsets[i].1 - reference.1))
+                .collect();
+            if tx.send(Some(chunk)).is_err() { break }
+        }
+        for _ in 0..threads {
+            let _ = tx.send(None);
+        }
+
+        let mut glitched = Vec::new();
+        let mut worst = (f64::INFINITY, 0);
+        for out in done_rx {
+            for (i, result) in out {
+                match result {
+                    Ok(result) => {
+                        results[i] = Some(result);
+                        stage.step()?;
+                    },
+                    // Out of references, so the pixel gets the interior color
+                    Err(_) if last_pass => {
+                        results[i] = Some(EscapeResult::interior(ctx.max_iter, (0.0, 0.0)));
+                        stage.step()?;
+                    },
+                    Err(severity) => {
+                        if severity < worst.0 { worst = (severity, i); }
+                        glitched.push(i);
+                    },
+                }
+            }
+        }
+
+        join(handles)?;
+
+        if glitched.is_empty() { break; }
+        // The most glitched pixel sits closest to whatever the old reference
+        // couldn't resolve, so it makes the best new reference
+        reference = offsets[worst.1];
+        glitched.sort();
+        pending = glitched;
+    }
+
+    let values = results.iter()
+        .map(|r| r.as_ref().map(|r| Sample::new(r, coloring, interior, &ctx)).ok_or(Error::Worker))
+        .collect::<Result<Vec<Sample>, Error>>()?;
+    Ok(colorize(&ctx, cs, &values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_deep_zoom_rgb;
+    use super::super::{render_image_rgb, ColorScheme, Coloring, Interior, Fractal, Monitor};
+    use super::super::formulas::{Mandelbrot, Multibrot};
+
+    #[test]
+    fn shallow_zoom_matches_f64() {
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0x000000, 0.0);
+        cs.add_hex(0xffffff, 1.0);
+        cs.set_coloring(Coloring::Linear);
+        // Depends on where the orbit of every interior point ended up
+        cs.set_interior(Interior::Magnitude);
+        let mut ctx = Mandelbrot.default_context();
+        ctx.x = (-0.75).into();
+        ctx.y = 0.1.into();
+        ctx.scale = 0.05;
+        ctx.max_iter = 500;
+        ctx.x_px = 64;
+        ctx.y_px = 64;
+
+        // A power 2 Multibrot iterates z^2 + c all the way to max_iter, where
+        // Mandelbrot stops at the cardioid or the first cycle it finds
+        let expected = render_image_rgb(ctx.clone(), &cs, Multibrot::new(2.0), &Monitor::new()).unwrap();
+        let deep = render_deep_zoom_rgb(ctx, &cs, &Monitor::new()).unwrap();
+        assert_eq!(deep.into_raw(), expected.into_raw());
+    }
+}
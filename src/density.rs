@@ -0,0 +1,321 @@
+use std::path::Path;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use super::{RenderingContext, ColorScheme, Monitor, Phase, Complex, Error};
+use super::error::join;
+use super::formulas::in_bulb;
+use super::rng::Rng;
+use num_cpus;
+use spmc;
+use image;
+use image::ImageBuffer;
+
+/// How many orbits are sent to a worker at once.
+const BATCH: u64 = 10000;
+
+/// How orbit counts are turned into brightness.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ToneMapping {
+    /// Proportional to the count
+    Linear,
+    /// Square root of the count, which keeps faint orbits visible
+    #[default]
+    Sqrt,
+    /// Logarithm of the count
+    Log,
+}
+
+impl ToneMapping {
+    fn map(&self, count: u64, max: u64) -> f64 {
+        if max == 0 { return 0.0 }
+        match *self {
+            ToneMapping::Linear => count as f64 / max as f64,
+            ToneMapping::Sqrt => (count as f64 / max as f64).sqrt(),
+            ToneMapping::Log => (count as f64).ln_1p() / (max as f64).ln_1p(),
+        }
+    }
+}
+
+/// Settings for `render_density`.
+#[derive(Clone, Debug)]
+pub struct Density {
+    /// Number of random points whose orbits are sampled
+    pub samples: u64,
+    /// Plot the orbits of points that never escape instead of the ones that do
+    pub anti: bool,
+    /// Escaping orbits shorter than this are left out
+    pub min_iter: u64,
+    /// Iteration limits of the red, green and blue channels. `None` renders
+    /// a single channel up to `ctx.max_iter`, colored with the `ColorScheme`.
+    pub channels: Option<[u64; 3]>,
+    pub tone: ToneMapping,
+}
+
+impl Density {
+    pub fn buddhabrot(samples: u64) -> Density {
+        Density { samples, anti: false, min_iter: 0, channels: None, tone: ToneMapping::Sqrt }
+    }
+
+    pub fn anti_buddhabrot(samples: u64) -> Density {
+        Density { anti: true, ..Density::buddhabrot(samples) }
+    }
+
+    /// A Buddhabrot with a different iteration limit in each channel.
+    pub fn nebulabrot(samples: u64, channels: [u64; 3]) -> Density {
+        Density { channels: Some(channels), ..Density::buddhabrot(samples) }
+    }
+
+    fn limits(&self, ctx: &RenderingContext) -> Vec<u64> {
+        match self.channels {
+            Some(channels) => channels.to_vec(),
+            None => vec![ctx.max_iter],
+        }
+    }
+}
+
+/// Fills `orbit` with the points of the orbit of `c` up to the one that
+/// escapes, returning how many iterations that took, or `None` if it didn't
+/// escape within `max_iter`.
+fn orbit(c: (f64, f64), max_iter: u64, orbit: &mut Vec<(f64, f64)>) -> Option<u64> {
+    orbit.clear();
+    let (mut x, mut y) = (0.0, 0.0);
+    for iter in 1..=max_iter {
+        let xtemp = x*x - y*y + c.0;
+        y = 2.0*x*y + c.1;
+        x = xtemp;
+        if x*x + y*y > 4.0 { return Some(iter) }
+        orbit.push((x, y));
+    }
+    None
+}
+
+/// Renders a Buddhabrot, Anti-Buddhabrot or Nebulabrot.
+///
+/// Random points `c` are picked from `[-2, 2]` in both directions and every
+/// point of their Mandelbrot orbit that lands inside the view described by
+/// `ctx` brightens that pixel. Samples are the same no matter how many threads
+/// there are, so renders are reproducible.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_density, ColorScheme, RenderingContext, Density, Precision, Strategy, Monitor};
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// let ctx = RenderingContext { x: (-0.4).into(), y: 0.0.into(), scale: 3.2, max_iter: 1000, x_px: 512, y_px: 512, precision: Precision::F64, strategy: Strategy::EveryPixel };
+/// render_density(ctx.clone(), &cs, &Path::new("buddhabrot.png"), &Density::buddhabrot(10000000), &Monitor::terminal()).unwrap();
+/// render_density(ctx, &cs, &Path::new("nebulabrot.png"), &Density::nebulabrot(10000000, [5000, 500, 50]), &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+pub fn render_density(ctx: RenderingContext, cs: &ColorScheme, path: &Path, density: &Density, monitor: &Monitor) -> Result<(), Error> {
+    image::ImageRgb8(render_density_rgb(ctx, cs, density, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_density` without the file, handing back the picture instead.
+pub fn render_density_rgb(ctx: RenderingContext, cs: &ColorScheme, density: &Density, monitor: &Monitor) -> Result<image::RgbImage, Error> {
+    ctx.validate()?;
+    let limits = density.limits(&ctx);
+    // Only a single channel is colored from the scheme
+    if limits.len() == 1 { cs.validate()?; }
+    let pixels = ctx.x_px as usize*ctx.y_px as usize;
+    if pixels == 0 { return Ok(ImageBuffer::new(ctx.x_px, ctx.y_px)) }
+
+    let counts = sample(&ctx, density, &limits, monitor, num_cpus::get())?;
+
+    let max : Vec<u64> = counts.chunks(pixels).map(|plane| *plane.iter().max().unwrap()).collect();
+    let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let i = x as usize + y as usize*ctx.x_px as usize;
+        if limits.len() == 1 {
+            *pixel = cs.get_color(density.tone.map(counts[i], max[0]));
+        } else {
+            let mut rgb = [0; 3];
+            for (channel, value) in rgb.iter_mut().enumerate() {
+                *value = (density.tone.map(counts[channel*pixels + i], max[channel])*255.0) as u8;
+            }
+            *pixel = image::Rgb(rgb);
+        }
+    }
+
+    Ok(img)
+}
+
+/// Traces the orbits of `density.samples` random points on `threads` threads
+/// and counts how often each channel's pixels are hit, channel after channel.
+fn sample(ctx: &RenderingContext, density: &Density, limits: &[u64], monitor: &Monitor, threads: usize) -> Result<Vec<u64>, Error> {
+    let pixels = ctx.x_px as usize*ctx.y_px as usize;
+    let mut buffers : Vec<Arc<Mutex<Vec<u64>>>> = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        buffers.push(Arc::new(Mutex::new(vec![0; pixels*limits.len()])));
+    }
+
+    let batches = density.samples.div_ceil(BATCH);
+    let stage = Arc::new(monitor.phase(Phase::Sampling, batches));
+
+    let mut handles = Vec::with_capacity(threads);
+    let (tx, rx) = spmc::channel();
+    let density = Arc::new(density.clone());
+    let limits = Arc::new(limits.to_vec());
+    for buffer in &buffers {
+        let rx = rx.clone();
+        let buffer = buffer.clone();
+        let density = density.clone();
+        let limits = limits.clone();
+        let stage = stage.clone();
+        let (cx, cy) = (ctx.x.to_f64(), ctx.y.to_f64());
+        let (x_scale, y_scale) = (ctx.scale, ctx.scale*(ctx.y_px as f64/ctx.x_px as f64));
+        let (x_px, y_px) = (ctx.x_px, ctx.y_px);
+        handles.push(thread::spawn(move || {
+            let mut buffer = buffer.lock()?;
+            let max_iter = *limits.iter().max().unwrap();
+            let mut points = Vec::with_capacity(max_iter as usize);
+            while let Some((batch, count)) = rx.recv()? {
+                let (batch, count) : (u64, u64) = (batch, count);
+                let mut rng = Rng::new(batch);
+                for _ in 0..count {
+                    let c = (rng.next()*4.0 - 2.0, rng.next()*4.0 - 2.0);
+                    if !density.anti && in_bulb(&Complex::new(c.0, c.1)).is_some() { continue; }
+                    let escaped = orbit(c, max_iter, &mut points);
+
+                    for (channel, &limit) in limits.iter().enumerate() {
+                        // Whether the orbit escaped depends on the channel's limit
+                        let length = match escaped {
+                            Some(iter) if iter <= limit => {
+                                if density.anti || iter < density.min_iter { continue; }
+                                points.len()
+                            },
+                            _ if density.anti => limit as usize,
+                            _ => continue,
+                        };
+                        let plane = &mut buffer[channel*pixels..(channel + 1)*pixels];
+                        for &(x, y) in &points[..length] {
+                            let px = ((x - cx)/x_scale + 0.5)*x_px as f64;
+                            let py = ((y - cy)/y_scale + 0.5)*y_px as f64;
+                            if px < 0.0 || py < 0.0 || px >= x_px as f64 || py >= y_px as f64 { continue; }
+                            plane[px as usize + py as usize*x_px as usize] += 1;
+                        }
+                    }
+                }
+                stage.step()?;
+            }
+            Ok(())
+        }));
+    }
+
+    for batch in 0..batches {
+        let count = BATCH.min(density.samples - batch*BATCH);
+        if tx.send(Some((batch, count))).is_err() { break }
+    }
+
+    for _ in 0..threads {
+        let _ = tx.send(None);
+    }
+
+    join(handles)?;
+
+    let mut counts = vec![0u64; pixels*limits.len()];
+    for buffer in &buffers {
+        for (total, &count) in counts.iter_mut().zip(buffer.lock()?.iter()) {
+            *total += count;
+        }
+    }
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample, orbit, render_density_rgb, Density, BATCH};
+    use super::super::{ColorScheme, Fractal, Monitor, RenderingContext};
+    use super::super::formulas::Mandelbrot;
+    use super::super::rng::Rng;
+
+    /// A view of all of `[-2, 2]`, which every point of an orbit before it
+    /// escapes lands in.
+    fn everything() -> RenderingContext {
+        let mut ctx = Mandelbrot.default_context();
+        ctx.x = 0.0.into();
+        ctx.y = 0.0.into();
+        ctx.scale = 4.5;
+        ctx.x_px = 45;
+        ctx.y_px = 45;
+        ctx.max_iter = 50;
+        ctx
+    }
+
+    fn counts(ctx: &RenderingContext, density: &Density, threads: usize) -> Vec<u64> {
+        sample(ctx, density, &density.limits(ctx), &Monitor::new(), threads).unwrap()
+    }
+
+    /// How many iterations the orbit of each sample point takes to escape.
+    fn escapes(samples: u64, max_iter: u64) -> Vec<Option<u64>> {
+        let mut points = Vec::new();
+        (0..samples.div_ceil(BATCH)).flat_map(|batch| {
+            let mut rng = Rng::new(batch);
+            (0..BATCH.min(samples - batch*BATCH)).map(move |_| (rng.next()*4.0 - 2.0, rng.next()*4.0 - 2.0))
+        }).map(|c| orbit(c, max_iter, &mut points)).collect()
+    }
+
+    #[test]
+    fn samples_do_not_depend_on_thread_count() {
+        let ctx = everything();
+        let density = Density::nebulabrot(25000, [100, 20, 5]);
+        let single = counts(&ctx, &density, 1);
+        assert!(single.iter().any(|&count| count > 0));
+        assert_eq!(counts(&ctx, &density, 4), single);
+        assert_eq!(counts(&ctx, &density, 7), single);
+    }
+
+    #[test]
+    fn anti_plots_only_orbits_that_stay() {
+        let ctx = everything();
+        let staying = escapes(25000, ctx.max_iter).iter().filter(|escaped| escaped.is_none()).count() as u64;
+        assert!(staying > 0);
+        let total : u64 = counts(&ctx, &Density::anti_buddhabrot(25000), 2).iter().sum();
+        assert_eq!(total, staying*ctx.max_iter);
+    }
+
+    #[test]
+    fn buddhabrot_plots_only_orbits_that_escape() {
+        let ctx = everything();
+        let mut density = Density::buddhabrot(25000);
+        density.min_iter = 5;
+        let expected : u64 = escapes(25000, ctx.max_iter).iter()
+            .filter_map(|&escaped| escaped)
+            .filter(|&iter| iter >= density.min_iter)
+            .map(|iter| iter - 1)
+            .sum();
+        assert!(expected > 0);
+        assert_eq!(counts(&ctx, &density, 2).iter().sum::<u64>(), expected);
+    }
+
+    #[test]
+    fn nebulabrot_channels_keep_their_own_limits() {
+        let limits = [200, 30, 5];
+        for &anti in &[false, true] {
+            let mut ctx = everything();
+            let nebulabrot = Density { anti, ..Density::nebulabrot(25000, limits) };
+            let planes = counts(&ctx, &nebulabrot, 2);
+            let pixels = planes.len()/3;
+            assert_ne!(planes[..pixels], planes[2*pixels..]);
+            for (plane, &limit) in planes.chunks(pixels).zip(&limits) {
+                ctx.max_iter = limit;
+                let single = Density { anti, ..Density::buddhabrot(25000) };
+                assert_eq!(plane, &counts(&ctx, &single, 2)[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_views_render_empty_images() {
+        let mut ctx = everything();
+        ctx.x_px = 0;
+        let img = render_density_rgb(ctx.clone(), &ColorScheme::new(), &Density::nebulabrot(100, [5, 5, 5]), &Monitor::new()).unwrap();
+        assert_eq!(img.dimensions(), (0, 45));
+        ctx.x_px = 45;
+        ctx.y_px = 0;
+        let img = render_density_rgb(ctx, &ColorScheme::new(), &Density::nebulabrot(100, [5, 5, 5]), &Monitor::new()).unwrap();
+        assert_eq!(img.dimensions(), (45, 0));
+    }
+}
@@ -0,0 +1,207 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Neg};
+use std::str::FromStr;
+use super::{BigFloat, ParseBigFloatError, Real};
+
+/// A number stored as the unevaluated sum of two `f64`s.
+///
+/// `lo` holds what `hi` rounds away, giving about 106 bits of mantissa. That
+/// is enough for zooms down to around `1e-30` while costing only a handful of
+/// `f64` operations per arithmetic operation, far less than a `BigFloat`.
+///
+/// ```
+/// # extern crate fractal;
+/// # use fractal::DoubleDouble;
+/// # fn main() {
+/// let one = DoubleDouble::from(1.0);
+/// let tiny = DoubleDouble::from(1e-20);
+/// assert_eq!(((one + tiny) - one).hi, 1e-20);
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DoubleDouble {
+    pub hi: f64,
+    pub lo: f64,
+}
+
+/// `a + b` and its rounding error.
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let bb = s - a;
+    (s, (a - (s - bb)) + (b - bb))
+}
+
+/// `two_sum` for when `|a| >= |b|` is already known.
+fn quick_two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    (s, b - (s - a))
+}
+
+/// Splits `a` into two halves with 26 bits of mantissa each, whose products are exact.
+fn split(a: f64) -> (f64, f64) {
+    let t = 134217729.0 * a;
+    let hi = t - (t - a);
+    (hi, a - hi)
+}
+
+/// `a * b` and its rounding error.
+fn two_prod(a: f64, b: f64) -> (f64, f64) {
+    let p = a * b;
+    let (a_hi, a_lo) = split(a);
+    let (b_hi, b_lo) = split(b);
+    (p, ((a_hi*b_hi - p) + a_hi*b_lo + a_lo*b_hi) + a_lo*b_lo)
+}
+
+impl DoubleDouble {
+    pub fn new(hi: f64, lo: f64) -> DoubleDouble {
+        let (hi, lo) = two_sum(hi, lo);
+        DoubleDouble { hi, lo }
+    }
+}
+
+impl From<f64> for DoubleDouble {
+    fn from(v: f64) -> DoubleDouble {
+        DoubleDouble { hi: v, lo: 0.0 }
+    }
+}
+
+impl Add for DoubleDouble {
+    type Output = DoubleDouble;
+    fn add(self, other: DoubleDouble) -> DoubleDouble {
+        let (s, e) = two_sum(self.hi, other.hi);
+        let (t, f) = two_sum(self.lo, other.lo);
+        let (s, e) = quick_two_sum(s, e + t);
+        let (hi, lo) = quick_two_sum(s, e + f);
+        DoubleDouble { hi, lo }
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = DoubleDouble;
+    fn sub(self, other: DoubleDouble) -> DoubleDouble { self + -other }
+}
+
+impl Mul for DoubleDouble {
+    type Output = DoubleDouble;
+    fn mul(self, other: DoubleDouble) -> DoubleDouble {
+        let (p, e) = two_prod(self.hi, other.hi);
+        let (hi, lo) = quick_two_sum(p, e + (self.hi*other.lo + self.lo*other.hi));
+        DoubleDouble { hi, lo }
+    }
+}
+
+impl Neg for DoubleDouble {
+    type Output = DoubleDouble;
+    fn neg(self) -> DoubleDouble { DoubleDouble { hi: -self.hi, lo: -self.lo } }
+}
+
+impl PartialEq for DoubleDouble {
+    fn eq(&self, other: &DoubleDouble) -> bool {
+        self.hi == other.hi && self.lo == other.lo
+    }
+}
+
+impl PartialOrd for DoubleDouble {
+    fn partial_cmp(&self, other: &DoubleDouble) -> Option<Ordering> {
+        match self.hi.partial_cmp(&other.hi) {
+            Some(Ordering::Equal) => self.lo.partial_cmp(&other.lo),
+            ord => ord,
+        }
+    }
+}
+
+impl Real for DoubleDouble {
+    fn from_f64(v: f64) -> DoubleDouble { DoubleDouble::from(v) }
+
+    fn from_big(v: &BigFloat) -> DoubleDouble {
+        let hi = v.to_f64();
+        DoubleDouble::new(hi, (v - &BigFloat::from(hi)).to_f64())
+    }
+
+    fn to_f64(&self) -> f64 { self.hi }
+
+    fn to_big(&self) -> BigFloat { &BigFloat::from(self.hi) + &BigFloat::from(self.lo) }
+
+    fn epsilon(&self) -> f64 { f64::EPSILON * f64::EPSILON }
+
+    fn abs(self) -> DoubleDouble {
+        if self.hi < 0.0 { -self } else { self }
+    }
+}
+
+impl FromStr for DoubleDouble {
+    type Err = ParseBigFloatError;
+
+    /// Parses the same notation as `BigFloat`, rounding to the nearest `DoubleDouble`.
+    fn from_str(s: &str) -> Result<DoubleDouble, ParseBigFloatError> {
+        Ok(DoubleDouble::from_big(&s.parse()?))
+    }
+}
+
+impl fmt::Display for DoubleDouble {
+    /// Prints the exact value of `hi + lo`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_big())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DoubleDouble;
+    use super::super::{BigFloat, Real};
+    use super::super::rng::Rng;
+
+    /// `2^-104`, a few units in the last place of a `DoubleDouble`.
+    const BOUND: f64 = 4.930380657631324e-32;
+
+    /// Random numbers with every bit of `lo` in use, spread over a few
+    /// orders of magnitude either side of 1.
+    fn samples() -> Vec<DoubleDouble> {
+        let mut rng = Rng::new(8);
+        (0..2000).map(|_| {
+            let hi = (rng.next()*2.0 - 1.0) * 2f64.powi((rng.next()*16.0) as i32 - 8);
+            DoubleDouble::new(hi, hi*f64::EPSILON*(rng.next() - 0.5))
+        }).collect()
+    }
+
+    /// Checks `|result - exact| <= BOUND*|exact|`, exactly.
+    fn close(result: DoubleDouble, exact: &BigFloat, what: &str) {
+        let error = (&result.to_big() - exact).abs();
+        let allowed = &exact.clone().abs() * &BigFloat::from(BOUND);
+        assert!(error <= allowed, "{} is off by {}, more than {}", what, error, allowed);
+    }
+
+    #[test]
+    fn arithmetic_is_within_the_bound() {
+        let samples = samples();
+        for pair in samples.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            // Plenty of bits for every result to be exact
+            let (x, y) = (a.to_big().with_precision(512), b.to_big().with_precision(512));
+            close(a + b, &(&x + &y), "sum");
+            close(a - b, &(&x - &y), "difference");
+            close(a * b, &(&x * &y), "product");
+            close(-a, &-&x, "negation");
+        }
+    }
+
+    #[test]
+    fn cancellation_keeps_the_low_part() {
+        for a in samples() {
+            let b = a + DoubleDouble::from(a.hi*1e-20);
+            let exact = &b.to_big() - &a.to_big();
+            close(b - a, &exact, "difference");
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for a in samples() {
+            assert_eq!(DoubleDouble::from_big(&a.to_big()), a);
+            assert_eq!(a.to_string().parse::<DoubleDouble>().unwrap(), a);
+        }
+        let tenth : BigFloat = "0.1".parse::<BigFloat>().unwrap().with_precision(256);
+        close("0.1".parse().unwrap(), &tenth, "0.1");
+    }
+}
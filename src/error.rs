@@ -0,0 +1,86 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+use std::sync::mpsc::RecvError;
+use std::thread::JoinHandle;
+
+/// Everything that can stop a render.
+#[derive(Debug)]
+pub enum Error {
+    /// Writing a picture out failed
+    Io(io::Error),
+    /// A `ColorScheme` without any colors to pick from
+    EmptyColorScheme,
+    /// A `ColorScheme` color at a position that isn't a number
+    ColorPosition(f64),
+    /// A `Flame` without any transforms, or whose weights add up to nothing
+    EmptyFlame,
+    /// A `Flame` transform weight that is negative or not a number
+    TransformWeight(f64),
+    /// A `Camera` with no direction to look in, because it sits on the
+    /// point it looks at or looks straight along its `up`
+    Camera,
+    /// A `RenderingContext` scale that isn't a positive number
+    Scale(f64),
+    /// A worker thread panicked, taking its part of the render with it
+    Worker,
+    /// The render's `Cancel` token was set
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "couldn't write image: {}", err),
+            Error::EmptyColorScheme => write!(f, "color scheme has no colors"),
+            Error::ColorPosition(position) => write!(f, "color scheme has a color at {}", position),
+            Error::EmptyFlame => write!(f, "flame has no transforms to pick"),
+            Error::TransformWeight(weight) => write!(f, "flame has a transform weighted {}", weight),
+            Error::Camera => write!(f, "camera has no direction to look in"),
+            Error::Scale(scale) => write!(f, "scale {} isn't a positive number", scale),
+            Error::Worker => write!(f, "a worker thread panicked"),
+            Error::Cancelled => write!(f, "the render was cancelled"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Locks are only poisoned by a thread panicking while it held them.
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Error {
+        Error::Worker
+    }
+}
+
+/// Channels only close early when every thread on the other end is gone.
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Error {
+        Error::Worker
+    }
+}
+
+/// Waits for every worker to finish, then hands back the first thing that
+/// went wrong in any of them.
+pub(crate) fn join(handles: Vec<JoinHandle<Result<(), Error>>>) -> Result<(), Error> {
+    let mut result = Ok(());
+    for handle in handles {
+        let outcome = handle.join().unwrap_or(Err(Error::Worker));
+        if result.is_ok() { result = outcome; }
+    }
+    result
+}
@@ -0,0 +1,598 @@
+use std::fmt;
+use std::str::FromStr;
+use super::{Fractal, EscapeResult, Complex};
+
+/// A formula parsed from text at runtime.
+///
+/// The iteration is an expression for the next `z`, optionally written as
+/// `z = ...`. Expressions can use `z`, the pixel `c`, the normalized time
+/// `t`, the constants `i`, `pi` and `e`, numbers like `1.5` or `2i`, the
+/// operators `+ - * / ^`, `|x|` for the absolute value and the functions
+/// `sin cos tan sinh cosh tanh exp log sqrt abs arg conj re im`.
+///
+/// The orbit starts at `z = 0` unless `initial` says otherwise, and escapes
+/// once `|z| > 2` unless `bailout` gives another comparison of two
+/// expressions, which compares their real parts. Expressions are compiled to
+/// a small stack machine, with constant parts worked out ahead of time. They
+/// can nest up to 100 deep.
+///
+/// ```
+/// # extern crate fractal;
+/// # use fractal::{Formula, Fractal};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// let mandelbrot = Formula::new("z = z^2 + c").unwrap();
+/// assert_eq!(mandelbrot.iterate(0.3, 0.5, 100, 0.0), Mandelbrot.iterate(0.3, 0.5, 100, 0.0));
+///
+/// let julia = Formula::new("z^3 + c*sin(z)").unwrap()
+///     .bailout("re(z)^2 > 100").unwrap()
+///     .initial("c").unwrap();
+/// assert!(julia.iterate(1.0, 1.0, 100, 0.0) < 100);
+///
+/// let err = Formula::new("z = z^2 + * c").unwrap_err();
+/// assert_eq!(err.column, 11);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Formula {
+    iteration: Program,
+    initial: Program,
+    bailout: Condition,
+}
+
+impl Formula {
+    pub fn new(iteration: &str) -> Result<Formula, ParseFormulaError> {
+        Ok(Formula {
+            iteration: Parser::new(iteration)?.statement()?,
+            initial: Program { ops: vec![Op::Const(Complex::new(0.0, 0.0))], depth: 1 },
+            bailout: Parser::new("|z| > 2")?.condition()?,
+        })
+    }
+
+    /// Escapes once `bailout`, a comparison like `|z| > 10`, holds.
+    pub fn bailout(self, bailout: &str) -> Result<Formula, ParseFormulaError> {
+        Ok(Formula { bailout: Parser::new(bailout)?.condition()?, ..self })
+    }
+
+    /// Starts the orbit at `initial`, an expression which may use `c` and `t`.
+    pub fn initial(self, initial: &str) -> Result<Formula, ParseFormulaError> {
+        Ok(Formula { initial: Parser::new(initial)?.statement()?, ..self })
+    }
+}
+
+impl FromStr for Formula {
+    type Err = ParseFormulaError;
+
+    fn from_str(s: &str) -> Result<Formula, ParseFormulaError> {
+        Formula::new(s)
+    }
+}
+
+impl Fractal for Formula {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.escape(x0, y0, max_iter, t).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_visit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let mut stack = Vec::with_capacity(self.iteration.depth.max(self.initial.depth).max(self.bailout.depth()));
+        let c = Complex::new(x0, y0);
+        let t = Complex::new(t, 0.0);
+        let mut z = self.initial.eval(Complex::new(0.0, 0.0), c, t, &mut stack);
+
+        for iter in 0..max_iter {
+            if self.bailout.holds(z, c, t, &mut stack) {
+                return EscapeResult::escaped(iter, iter as f64, (z.re, z.im));
+            }
+            z = self.iteration.eval(z, c, t, &mut stack);
+            visit(z.re, z.im);
+        }
+
+        EscapeResult::interior(max_iter, (z.re, z.im))
+    }
+
+    fn name(&self) -> &str { "formula" }
+}
+
+/// Where and why a formula didn't parse.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseFormulaError {
+    /// Column of the offending character, counting from 1
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseFormulaError {
+    fn new(column: usize, message: String) -> ParseFormulaError {
+        ParseFormulaError { column, message }
+    }
+}
+
+impl fmt::Display for ParseFormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Sin, Cos, Tan, Sinh, Cosh, Tanh, Exp, Log, Sqrt, Abs, Arg, Conj, Re, Im,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "sinh" => Function::Sinh,
+            "cosh" => Function::Cosh,
+            "tanh" => Function::Tanh,
+            "exp" => Function::Exp,
+            "log" => Function::Log,
+            "sqrt" => Function::Sqrt,
+            "abs" => Function::Abs,
+            "arg" => Function::Arg,
+            "conj" => Function::Conj,
+            "re" => Function::Re,
+            "im" => Function::Im,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, z: Complex<f64>) -> Complex<f64> {
+        match self {
+            Function::Sin => z.sin(),
+            Function::Cos => z.cos(),
+            Function::Tan => z.sin()/z.cos(),
+            Function::Sinh => z.sinh(),
+            Function::Cosh => z.cosh(),
+            Function::Tanh => z.sinh()/z.cosh(),
+            Function::Exp => z.exp(),
+            Function::Log => z.ln(),
+            Function::Sqrt => z.sqrt(),
+            Function::Abs => Complex::new(z.norm(), 0.0),
+            Function::Arg => Complex::new(z.arg(), 0.0),
+            Function::Conj => z.conj(),
+            Function::Re => Complex::new(z.re, 0.0),
+            Function::Im => Complex::new(z.im, 0.0),
+        }
+    }
+}
+
+/// One instruction of the stack machine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Const(Complex<f64>),
+    Z,
+    C,
+    T,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    /// Power by a constant integer, which is much faster than `Pow`
+    Powi(i32),
+    Neg,
+    Call(Function),
+}
+
+#[derive(Clone, Debug)]
+struct Program {
+    ops: Vec<Op>,
+    /// Deepest the stack gets
+    depth: usize,
+}
+
+impl Program {
+    fn eval(&self, z: Complex<f64>, c: Complex<f64>, t: Complex<f64>, stack: &mut Vec<Complex<f64>>) -> Complex<f64> {
+        stack.clear();
+        for &op in &self.ops {
+            let value = match op {
+                Op::Const(value) => value,
+                Op::Z => z,
+                Op::C => c,
+                Op::T => t,
+                Op::Neg => -stack.pop().unwrap(),
+                Op::Powi(n) => stack.pop().unwrap().powi(n),
+                Op::Call(function) => function.apply(stack.pop().unwrap()),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    binary(op, a, b)
+                },
+            };
+            stack.push(value);
+        }
+        stack.pop().unwrap()
+    }
+}
+
+fn binary(op: Op, a: Complex<f64>, b: Complex<f64>) -> Complex<f64> {
+    match op {
+        Op::Add => a + b,
+        Op::Sub => a - b,
+        Op::Mul => a*b,
+        Op::Div => a/b,
+        Op::Pow => a.powc(b),
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison { Less, LessEqual, Greater, GreaterEqual }
+
+#[derive(Clone, Debug)]
+struct Condition {
+    left: Program,
+    comparison: Comparison,
+    right: Program,
+}
+
+impl Condition {
+    fn depth(&self) -> usize { self.left.depth.max(self.right.depth) }
+
+    fn holds(&self, z: Complex<f64>, c: Complex<f64>, t: Complex<f64>, stack: &mut Vec<Complex<f64>>) -> bool {
+        let left = self.left.eval(z, c, t, stack).re;
+        let right = self.right.eval(z, c, t, stack).re;
+        match self.comparison {
+            Comparison::Less => left < right,
+            Comparison::LessEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterEqual => left >= right,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    /// A number followed by `i`
+    Imaginary(f64),
+    Name(String),
+    Symbol(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(v) => write!(f, "`{}`", v),
+            Token::Imaginary(v) => write!(f, "`{}i`", v),
+            Token::Name(ref name) => write!(f, "`{}`", name),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+            Token::End => write!(f, "end of formula"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 13] = ["<=", ">=", "<", ">", "+", "-", "*", "/", "^", "(", ")", "|", "="];
+
+/// Splits `s` into tokens, each with the column it starts at.
+fn tokenize(s: &str) -> Result<Vec<(Token, usize)>, ParseFormulaError> {
+    let chars : Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    'outer: while i < chars.len() {
+        let column = i + 1;
+        let ch = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if ch.is_ascii_digit() || ch == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') { i += 1; }
+            // Exponents, as long as the e is followed by a number
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') { j += 1; }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() { i += 1; }
+                }
+            }
+            let text : String = chars[start..i].iter().collect();
+            let value = text.parse().map_err(|_| ParseFormulaError::new(column, format!("invalid number `{}`", text)))?;
+            // `2i` is imaginary, but `2in` would be a number stuck to a name
+            if i < chars.len() && chars[i] == 'i' && !chars.get(i + 1).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                i += 1;
+                tokens.push((Token::Imaginary(value), column));
+            } else {
+                tokens.push((Token::Number(value), column));
+            }
+            continue;
+        }
+
+        if ch.is_alphabetic() || ch == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') { i += 1; }
+            tokens.push((Token::Name(chars[start..i].iter().collect()), column));
+            continue;
+        }
+
+        for symbol in SYMBOLS.iter() {
+            if symbol.chars().enumerate().all(|(j, c)| chars.get(i + j) == Some(&c)) {
+                i += symbol.len();
+                tokens.push((Token::Symbol(symbol), column));
+                continue 'outer;
+            }
+        }
+        return Err(ParseFormulaError::new(column, format!("unexpected `{}`", ch)));
+    }
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+/// How deeply brackets, functions, signs and powers can nest before a
+/// formula is turned down rather than overflowing the parser's stack.
+const MAX_DEPTH: usize = 100;
+
+/// Recursive descent parser that emits ops as it goes.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    ops: Vec<Op>,
+    /// How many `unary`s are in progress, which every kind of nesting goes through
+    depth: usize,
+}
+
+impl Parser {
+    fn new(s: &str) -> Result<Parser, ParseFormulaError> {
+        Ok(Parser { tokens: tokenize(s)?, pos: 0, ops: Vec::new(), depth: 0 })
+    }
+
+    fn peek(&self) -> &Token { &self.tokens[self.pos].0 }
+
+    fn column(&self) -> usize { self.tokens[self.pos].1 }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::End { self.pos += 1; }
+        token
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ParseFormulaError> {
+        Err(ParseFormulaError::new(self.column(), message))
+    }
+
+    fn unexpected<T>(&self) -> Result<T, ParseFormulaError> {
+        self.error(format!("unexpected {}", self.peek()))
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        match *self.peek() {
+            Token::Symbol(s) if s == symbol => {
+                self.pos += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ParseFormulaError> {
+        if self.eat(symbol) { Ok(()) } else { self.error(format!("expected `{}` but found {}", symbol, self.peek())) }
+    }
+
+    fn finish(&mut self) -> Result<(), ParseFormulaError> {
+        if *self.peek() != Token::End { return self.unexpected() }
+        Ok(())
+    }
+
+    /// Moves the ops emitted so far into a `Program`.
+    fn take(&mut self) -> Program {
+        let mut depth = 0;
+        let mut max = 0;
+        for op in &self.ops {
+            match *op {
+                Op::Const(_) | Op::Z | Op::C | Op::T => depth += 1,
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => depth -= 1,
+                Op::Powi(_) | Op::Neg | Op::Call(_) => (),
+            }
+            max = usize::max(max, depth);
+        }
+        Program { ops: self.ops.split_off(0), depth: max }
+    }
+
+    /// An expression, optionally preceded by `z =`.
+    fn statement(mut self) -> Result<Program, ParseFormulaError> {
+        if let (Token::Name(name), Some(&(Token::Symbol("="), _))) = (self.peek(), self.tokens.get(self.pos + 1)) {
+            if name != "z" { return self.error(format!("only `z` can be assigned to, not `{}`", name)) }
+            self.pos += 2;
+        }
+        self.expression()?;
+        self.finish()?;
+        Ok(self.take())
+    }
+
+    /// Two expressions compared with `<`, `<=`, `>` or `>=`.
+    fn condition(mut self) -> Result<Condition, ParseFormulaError> {
+        self.expression()?;
+        let left = self.take();
+        let comparison = match *self.peek() {
+            Token::Symbol("<") => Comparison::Less,
+            Token::Symbol("<=") => Comparison::LessEqual,
+            Token::Symbol(">") => Comparison::Greater,
+            Token::Symbol(">=") => Comparison::GreaterEqual,
+            _ => return self.error(format!("expected a comparison like `|z| > 2` but found {}", self.peek())),
+        };
+        self.pos += 1;
+        self.expression()?;
+        self.finish()?;
+        Ok(Condition { left, comparison, right: self.take() })
+    }
+
+    fn expression(&mut self) -> Result<(), ParseFormulaError> {
+        self.term()?;
+        loop {
+            if self.eat("+") { self.term()?; self.emit(Op::Add); }
+            else if self.eat("-") { self.term()?; self.emit(Op::Sub); }
+            else { return Ok(()) }
+        }
+    }
+
+    fn term(&mut self) -> Result<(), ParseFormulaError> {
+        self.unary()?;
+        loop {
+            if self.eat("*") { self.unary()?; self.emit(Op::Mul); }
+            else if self.eat("/") { self.unary()?; self.emit(Op::Div); }
+            else { return Ok(()) }
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), ParseFormulaError> {
+        if self.depth == MAX_DEPTH { return self.error(format!("nested more than {} deep", MAX_DEPTH)) }
+        self.depth += 1;
+        let result = if self.eat("-") {
+            self.unary().map(|()| self.emit(Op::Neg))
+        } else {
+            self.power()
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// `^` binds tighter than a leading `-` and groups to the right.
+    fn power(&mut self) -> Result<(), ParseFormulaError> {
+        self.atom()?;
+        if self.eat("^") {
+            self.unary()?;
+            self.emit(Op::Pow);
+        }
+        Ok(())
+    }
+
+    fn atom(&mut self) -> Result<(), ParseFormulaError> {
+        let column = self.column();
+        match self.next() {
+            Token::Number(v) => self.emit(Op::Const(Complex::new(v, 0.0))),
+            Token::Imaginary(v) => self.emit(Op::Const(Complex::new(0.0, v))),
+            Token::Symbol("(") => {
+                self.expression()?;
+                self.expect(")")?;
+            },
+            Token::Symbol("|") => {
+                self.expression()?;
+                self.expect("|")?;
+                self.emit(Op::Call(Function::Abs));
+            },
+            Token::Name(name) => match name.as_str() {
+                "z" => self.emit(Op::Z),
+                "c" => self.emit(Op::C),
+                "t" => self.emit(Op::T),
+                "i" => self.emit(Op::Const(Complex::new(0.0, 1.0))),
+                "pi" => self.emit(Op::Const(Complex::new(::std::f64::consts::PI, 0.0))),
+                "e" => self.emit(Op::Const(Complex::new(::std::f64::consts::E, 0.0))),
+                _ => match Function::from_name(&name) {
+                    Some(function) => {
+                        self.expect("(")?;
+                        self.expression()?;
+                        self.expect(")")?;
+                        self.emit(Op::Call(function));
+                    },
+                    None => return Err(ParseFormulaError::new(column, format!("unknown name `{}`", name))),
+                },
+            },
+            token => return Err(ParseFormulaError::new(column, format!("unexpected {}", token))),
+        }
+        Ok(())
+    }
+
+    /// Pushes `op`, working it out right away if its arguments are constants.
+    fn emit(&mut self, op: Op) {
+        let len = self.ops.len();
+        match op {
+            Op::Neg | Op::Call(_) => if let Some(&Op::Const(a)) = self.ops.last() {
+                self.ops[len - 1] = Op::Const(match op {
+                    Op::Call(function) => function.apply(a),
+                    _ => -a,
+                });
+                return;
+            },
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                if let (Some(&Op::Const(a)), Some(&Op::Const(b))) = (self.ops.get(len.wrapping_sub(2)), self.ops.last()) {
+                    self.ops.truncate(len - 2);
+                    self.ops.push(Op::Const(binary(op, a, b)));
+                    return;
+                }
+                // Small whole powers are multiplied out
+                if let (Op::Pow, Some(&Op::Const(b))) = (op, self.ops.last()) {
+                    if b.im == 0.0 && b.re.fract() == 0.0 && b.re.abs() <= 64.0 {
+                        self.ops[len - 1] = Op::Powi(b.re as i32);
+                        return;
+                    }
+                }
+            },
+            _ => (),
+        }
+        self.ops.push(op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Formula, Parser, Op, Function, MAX_DEPTH};
+    use super::super::Complex;
+
+    fn ops(s: &str) -> Vec<Op> {
+        Parser::new(s).unwrap().statement().unwrap().ops
+    }
+
+    fn constant(re: f64, im: f64) -> Op {
+        Op::Const(Complex::new(re, im))
+    }
+
+    fn column(s: &str) -> usize {
+        Formula::new(s).unwrap_err().column
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        assert_eq!(ops("2*3 + z"), vec![constant(6.0, 0.0), Op::Z, Op::Add]);
+        assert_eq!(ops("-(1 + 2i)"), vec![constant(-1.0, -2.0)]);
+        assert_eq!(ops("(1 + 1)*(2 - 0.5)/3"), vec![constant(1.0, 0.0)]);
+        assert_eq!(ops("z*sqrt(4)"), vec![Op::Z, constant(2.0, 0.0), Op::Mul]);
+        // Only constants next to each other on the stack are combined
+        assert_eq!(ops("z + 1 + 2"), vec![Op::Z, constant(1.0, 0.0), Op::Add, constant(2.0, 0.0), Op::Add]);
+        assert_eq!(ops("sin(c)"), vec![Op::C, Op::Call(Function::Sin)]);
+    }
+
+    #[test]
+    fn whole_powers_are_multiplied_out() {
+        assert_eq!(ops("z^2 + c"), vec![Op::Z, Op::Powi(2), Op::C, Op::Add]);
+        assert_eq!(ops("z^-3"), vec![Op::Z, Op::Powi(-3)]);
+        assert_eq!(ops("z^(1/2)"), vec![Op::Z, constant(0.5, 0.0), Op::Pow]);
+        assert_eq!(ops("z^65"), vec![Op::Z, constant(65.0, 0.0), Op::Pow]);
+        assert_eq!(ops("z^2i"), vec![Op::Z, constant(0.0, 2.0), Op::Pow]);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(column("z = z^2 + * c"), 11);
+        assert_eq!(column("z^2 + w"), 7);
+        assert_eq!(column("x = z^2"), 1);
+        assert_eq!(column("sin z"), 5);
+        assert_eq!(column("(z + 1"), 7);
+        assert_eq!(column("z $ 1"), 3);
+        assert_eq!(column("z + 1.2.3"), 5);
+        assert_eq!(column("z z"), 3);
+        assert_eq!(Formula::new("z").unwrap().bailout("|z| 2").unwrap_err().column, 5);
+        assert_eq!(Formula::new("z").unwrap().initial("c +").unwrap_err().column, 4);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}z{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Formula::new(&nested(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(column(&nested(MAX_DEPTH)), MAX_DEPTH + 1);
+        assert_eq!(column(&nested(100_000)), MAX_DEPTH + 1);
+        assert_eq!(column(&format!("{}z", "-".repeat(100_000))), MAX_DEPTH + 1);
+        assert_eq!(column(&format!("{}z{}", "sin(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH))), 4*MAX_DEPTH + 1);
+        assert_eq!(column(&format!("z{}", "^z".repeat(MAX_DEPTH))), 2*MAX_DEPTH + 1);
+    }
+}
@@ -0,0 +1,451 @@
+use std::path::Path;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::f64::consts::PI;
+use super::{RenderingContext, ColorScheme, Monitor, Phase, Error};
+use super::error::join;
+use super::rng::Rng;
+use num_cpus;
+use spmc;
+use image;
+use image::ImageBuffer;
+
+/// How many points are sent to a worker at once.
+const BATCH: u64 = 10000;
+
+/// Iterations each batch takes before plotting, to land on the attractor.
+const FUSE: u32 = 20;
+
+/// Entries in the palette looked up from the `ColorScheme`.
+const PALETTE: usize = 256;
+
+/// The nonlinear functions applied after a transform's affine part, as
+/// numbered in the original flame paper.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variation {
+    Linear,
+    Sinusoidal,
+    Spherical,
+    Swirl,
+    Horseshoe,
+    Polar,
+    Handkerchief,
+    Heart,
+    Disc,
+    Spiral,
+    Hyperbolic,
+    Diamond,
+    /// Square root, picking either of the two roots at random
+    Julia,
+}
+
+impl Variation {
+    fn apply(&self, x: f64, y: f64, rng: &mut Rng) -> (f64, f64) {
+        let r2 = x*x + y*y;
+        let r = r2.sqrt();
+        let theta = x.atan2(y);
+        match *self {
+            Variation::Linear => (x, y),
+            Variation::Sinusoidal => (x.sin(), y.sin()),
+            Variation::Spherical => (x/r2, y/r2),
+            Variation::Swirl => (x*r2.sin() - y*r2.cos(), x*r2.cos() + y*r2.sin()),
+            Variation::Horseshoe => ((x - y)*(x + y)/r, 2.0*x*y/r),
+            Variation::Polar => (theta/PI, r - 1.0),
+            Variation::Handkerchief => (r*(theta + r).sin(), r*(theta - r).cos()),
+            Variation::Heart => (r*(theta*r).sin(), -r*(theta*r).cos()),
+            Variation::Disc => (theta/PI*(PI*r).sin(), theta/PI*(PI*r).cos()),
+            Variation::Spiral => (((theta).cos() + r.sin())/r, (theta.sin() - r.cos())/r),
+            Variation::Hyperbolic => (theta.sin()/r, r*theta.cos()),
+            Variation::Diamond => (theta.sin()*r.cos(), theta.cos()*r.sin()),
+            Variation::Julia => {
+                let omega = if rng.next() < 0.5 { 0.0 } else { PI };
+                let sqrt = r.sqrt();
+                (sqrt*(theta/2.0 + omega).cos(), sqrt*(theta/2.0 + omega).sin())
+            },
+        }
+    }
+}
+
+/// One of the functions the chaos game picks from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transform {
+    /// `[a, b, c, d, e, f]` taking `(x, y)` to `(a*x + b*y + c, d*x + e*y + f)`
+    pub affine: [f64; 6],
+    /// Weighted sum of variations applied to the result of `affine`
+    pub variations: Vec<(Variation, f64)>,
+    /// How often this transform is picked compared to the others
+    pub weight: f64,
+    /// Position in the `ColorScheme` points are pulled towards
+    pub color: f64,
+}
+
+impl Transform {
+    /// A purely affine transform.
+    pub fn new(affine: [f64; 6], weight: f64, color: f64) -> Transform {
+        Transform { affine, variations: vec![(Variation::Linear, 1.0)], weight, color }
+    }
+
+    fn apply(&self, x: f64, y: f64, rng: &mut Rng) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.affine;
+        let (x, y) = (a*x + b*y + c, d*x + e*y + f);
+        let mut out = (0.0, 0.0);
+        for &(variation, weight) in &self.variations {
+            let (vx, vy) = variation.apply(x, y, rng);
+            out = (out.0 + weight*vx, out.1 + weight*vy);
+        }
+        out
+    }
+}
+
+/// Settings for `render_flame`.
+#[derive(Clone, Debug)]
+pub struct Flame {
+    pub transforms: Vec<Transform>,
+    /// Number of points plotted
+    pub samples: u64,
+    /// Applied to the log density, bigger brings out fainter parts
+    pub gamma: f64,
+}
+
+impl Flame {
+    pub fn new(transforms: Vec<Transform>, samples: u64) -> Flame {
+        Flame { transforms, samples, gamma: 2.2 }
+    }
+
+    /// The Sierpinski triangle, as a plain IFS.
+    pub fn sierpinski(samples: u64) -> Flame {
+        Flame::new(vec![
+            Transform::new([0.5, 0.0, 0.0, 0.0, 0.5, 0.0], 1.0, 0.0),
+            Transform::new([0.5, 0.0, 0.5, 0.0, 0.5, 0.0], 1.0, 0.5),
+            Transform::new([0.5, 0.0, 0.0, 0.0, 0.5, 0.5], 1.0, 1.0),
+        ], samples)
+    }
+
+    /// Checks there is a transform to pick and every weight is a
+    /// number no less than zero. `render_flame` does this before starting.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(transform) = self.transforms.iter().find(|t| !(t.weight.is_finite() && t.weight >= 0.0)) {
+            return Err(Error::TransformWeight(transform.weight))
+        }
+        if self.transforms.iter().all(|t| t.weight == 0.0) { return Err(Error::EmptyFlame) }
+        Ok(())
+    }
+
+    /// Picks a transform by weight, with `u` uniform in `[0, 1)`.
+    fn pick(&self, u: f64) -> &Transform {
+        let total : f64 = self.transforms.iter().map(|t| t.weight).sum();
+        let mut u = u*total;
+        for transform in &self.transforms {
+            if u < transform.weight { return transform }
+            u -= transform.weight;
+        }
+        self.transforms.last().unwrap()
+    }
+}
+
+/// Renders a fractal flame with the chaos game.
+///
+/// Each batch of points starts from a random spot in `[-1, 1]` and jumps
+/// through randomly picked transforms, and every point that lands in the
+/// view described by `ctx` adds the color its transforms pulled it towards to
+/// that pixel. Pixels get the average color they were hit with, brightened by
+/// the log of how often that was. Like `render_density` the samples are the
+/// same no matter how many threads there are. `ctx.max_iter` is unused.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_flame, ColorScheme, Flame, Transform, Variation, RenderingContext, Precision, Strategy, Monitor};
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// let ctx = RenderingContext { x: 0.5.into(), y: 0.5.into(), scale: 1.2, max_iter: 0, x_px: 512, y_px: 512, precision: Precision::F64, strategy: Strategy::EveryPixel };
+/// render_flame(ctx, &cs, &Path::new("sierpinski.png"), &Flame::sierpinski(10000000), &Monitor::terminal()).unwrap();
+///
+/// let flame = Flame::new(vec![
+///     Transform {
+///         variations: vec![(Variation::Spherical, 0.5), (Variation::Linear, 0.5)],
+///         ..Transform::new([0.56, -0.32, 0.0, 0.32, 0.56, 0.0], 1.0, 0.0)
+///     },
+///     Transform {
+///         variations: vec![(Variation::Swirl, 1.0)],
+///         ..Transform::new([0.5, 0.0, 1.0, 0.0, 0.5, 0.0], 1.0, 0.5)
+///     },
+///     Transform {
+///         variations: vec![(Variation::Julia, 1.0)],
+///         ..Transform::new([-0.4, 0.3, -0.5, -0.3, -0.4, 0.8], 1.0, 1.0)
+///     },
+/// ], 50000000);
+/// let ctx = RenderingContext { x: 0.0.into(), y: 0.0.into(), scale: 4.0, max_iter: 0, x_px: 512, y_px: 512, precision: Precision::F64, strategy: Strategy::EveryPixel };
+/// render_flame(ctx, &cs, &Path::new("flame.png"), &flame, &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+pub fn render_flame(ctx: RenderingContext, cs: &ColorScheme, path: &Path, flame: &Flame, monitor: &Monitor) -> Result<(), Error> {
+    image::ImageRgb8(render_flame_rgb(ctx, cs, flame, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_flame` without the file, handing back the picture instead.
+pub fn render_flame_rgb(ctx: RenderingContext, cs: &ColorScheme, flame: &Flame, monitor: &Monitor) -> Result<image::RgbImage, Error> {
+    ctx.validate()?;
+    cs.validate()?;
+    flame.validate()?;
+    let pixels = ctx.x_px as usize*ctx.y_px as usize;
+    let palette : Vec<[f64; 3]> = (0..PALETTE).map(|i| {
+        let color = cs.get_color(i as f64 / (PALETTE - 1) as f64).data;
+        [color[0] as f64, color[1] as f64, color[2] as f64]
+    }).collect();
+    let palette = Arc::new(palette);
+
+    // Hits followed by the red, green and blue they added up to
+    let threads = num_cpus::get();
+    let mut buffers : Vec<Arc<Mutex<Vec<[f64; 4]>>>> = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        buffers.push(Arc::new(Mutex::new(vec![[0.0; 4]; pixels])));
+    }
+
+    let batches = flame.samples.div_ceil(BATCH);
+    let stage = Arc::new(monitor.phase(Phase::Sampling, batches));
+
+    let mut handles = Vec::with_capacity(threads);
+    let (tx, rx) = spmc::channel();
+    let flame = Arc::new(flame.clone());
+    for buffer in &buffers {
+        let rx = rx.clone();
+        let buffer = buffer.clone();
+        let flame = flame.clone();
+        let palette = palette.clone();
+        let stage = stage.clone();
+        let (cx, cy) = (ctx.x.to_f64(), ctx.y.to_f64());
+        let (x_scale, y_scale) = (ctx.scale, ctx.scale*(ctx.y_px as f64/ctx.x_px as f64));
+        let (x_px, y_px) = (ctx.x_px, ctx.y_px);
+        handles.push(thread::spawn(move || {
+            let mut buffer = buffer.lock()?;
+            while let Some((batch, count)) = rx.recv()? {
+                let (batch, count) : (u64, u64) = (batch, count);
+                let mut rng = Rng::new(batch);
+                let (mut x, mut y) = (rng.next()*2.0 - 1.0, rng.next()*2.0 - 1.0);
+                let mut color = rng.next();
+                let mut fuse = FUSE;
+                let mut plotted = 0;
+                let mut restarts = 0;
+                while plotted < count && restarts < count {
+                    let transform = flame.pick(rng.next());
+                    let (nx, ny) = transform.apply(x, y, &mut rng);
+                    if !nx.is_finite() || !ny.is_finite() {
+                        // Blown up by a singular variation, start over somewhere else
+                        x = rng.next()*2.0 - 1.0;
+                        y = rng.next()*2.0 - 1.0;
+                        fuse = FUSE;
+                        restarts += 1;
+                        continue;
+                    }
+                    x = nx;
+                    y = ny;
+                    color = (color + transform.color) / 2.0;
+                    if fuse > 0 {
+                        fuse -= 1;
+                        continue;
+                    }
+                    plotted += 1;
+
+                    let px = ((x - cx)/x_scale + 0.5)*x_px as f64;
+                    let py = ((y - cy)/y_scale + 0.5)*y_px as f64;
+                    if px < 0.0 || py < 0.0 || px >= x_px as f64 || py >= y_px as f64 { continue; }
+                    let rgb = palette[((color*PALETTE as f64) as usize).min(PALETTE - 1)];
+                    let pixel = &mut buffer[px as usize + py as usize*x_px as usize];
+                    pixel[0] += 1.0;
+                    pixel[1] += rgb[0];
+                    pixel[2] += rgb[1];
+                    pixel[3] += rgb[2];
+                }
+                stage.step()?;
+            }
+            Ok(())
+        }));
+    }
+
+    for batch in 0..batches {
+        let count = BATCH.min(flame.samples - batch*BATCH);
+        if tx.send(Some((batch, count))).is_err() { break }
+    }
+
+    for _ in 0..threads {
+        let _ = tx.send(None);
+    }
+
+    join(handles)?;
+
+    let mut totals = vec![[0.0; 4]; pixels];
+    for buffer in &buffers {
+        for (total, hits) in totals.iter_mut().zip(buffer.lock()?.iter()) {
+            for channel in 0..4 {
+                total[channel] += hits[channel];
+            }
+        }
+    }
+
+    let max = totals.iter().map(|hits| hits[0]).fold(0.0, f64::max);
+    let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let hits = totals[x as usize + y as usize*ctx.x_px as usize];
+        if hits[0] == 0.0 {
+            *pixel = image::Rgb([0, 0, 0]);
+            continue;
+        }
+        let brightness = (hits[0].ln_1p() / max.ln_1p()).powf(1.0 / flame.gamma);
+        *pixel = image::Rgb([
+            (hits[1]/hits[0]*brightness) as u8,
+            (hits[2]/hits[0]*brightness) as u8,
+            (hits[3]/hits[0]*brightness) as u8,
+        ]);
+    }
+
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Variation, Transform, Flame, render_flame_rgb};
+    use super::super::{ColorScheme, Error, Monitor, RenderingContext, Precision, Strategy};
+    use super::super::rng::Rng;
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-12 && (a.1 - b.1).abs() < 1e-12
+    }
+
+    /// Values from the formulas in the flame paper, where `theta` is the
+    /// angle from the y axis.
+    #[test]
+    fn variations_on_the_unit_circle() {
+        let (sin1, cos1) = (1f64.sin(), 1f64.cos());
+        // At (0, 1) r is 1 and theta is 0
+        let up = [
+            (Variation::Linear, (0.0, 1.0)),
+            (Variation::Sinusoidal, (0.0, sin1)),
+            (Variation::Spherical, (0.0, 1.0)),
+            (Variation::Swirl, (-cos1, sin1)),
+            (Variation::Horseshoe, (-1.0, 0.0)),
+            (Variation::Polar, (0.0, 0.0)),
+            (Variation::Handkerchief, (sin1, cos1)),
+            (Variation::Heart, (0.0, -1.0)),
+            (Variation::Disc, (0.0, 0.0)),
+            (Variation::Spiral, (1.0 + sin1, -cos1)),
+            (Variation::Hyperbolic, (0.0, 1.0)),
+            (Variation::Diamond, (0.0, sin1)),
+        ];
+        // At (1, 0) theta is a quarter turn
+        let right = [
+            (Variation::Polar, (0.5, 0.0)),
+            (Variation::Heart, (1.0, 0.0)),
+            (Variation::Disc, (0.0, -0.5)),
+            (Variation::Hyperbolic, (1.0, 0.0)),
+            (Variation::Diamond, (cos1, 0.0)),
+            (Variation::Horseshoe, (1.0, 0.0)),
+        ];
+        let mut rng = Rng::new(0);
+        for &(variation, expected) in &up {
+            let out = variation.apply(0.0, 1.0, &mut rng);
+            assert!(close(out, expected), "{:?} gave {:?}", variation, out);
+        }
+        for &(variation, expected) in &right {
+            let out = variation.apply(1.0, 0.0, &mut rng);
+            assert!(close(out, expected), "{:?} gave {:?}", variation, out);
+        }
+    }
+
+    #[test]
+    fn spherical_is_an_inversion() {
+        let mut rng = Rng::new(1);
+        for &(x, y) in &[(0.3, -0.4), (2.0, 5.0), (-0.01, 0.02)] {
+            let (sx, sy) = Variation::Spherical.apply(x, y, &mut rng);
+            assert!(((sx*sx + sy*sy)*(x*x + y*y) - 1.0).abs() < 1e-12);
+            assert!(close(Variation::Spherical.apply(sx, sy, &mut rng), (x, y)));
+        }
+    }
+
+    #[test]
+    fn julia_picks_either_root() {
+        let mut rng = Rng::new(2);
+        // Half of theta, at the square root of r = 5
+        let half = 3f64.atan2(4.0)/2.0;
+        let root = (5f64.sqrt()*half.cos(), 5f64.sqrt()*half.sin());
+        let mut seen = (false, false);
+        for _ in 0..100 {
+            let out = Variation::Julia.apply(3.0, 4.0, &mut rng);
+            if close(out, root) { seen.0 = true; }
+            else if close(out, (-root.0, -root.1)) { seen.1 = true; }
+            else { panic!("{:?} is neither root", out); }
+        }
+        assert_eq!(seen, (true, true));
+    }
+
+    #[test]
+    fn singular_variations_blow_up_instead_of_panicking() {
+        let mut rng = Rng::new(4);
+        for &variation in &[Variation::Spherical, Variation::Horseshoe, Variation::Spiral, Variation::Hyperbolic] {
+            let (x, y) = variation.apply(0.0, 0.0, &mut rng);
+            assert!(!x.is_finite() || !y.is_finite(), "{:?}", variation);
+        }
+    }
+
+    #[test]
+    fn transforms_weight_their_variations() {
+        let mut rng = Rng::new(3);
+        let transform = Transform {
+            variations: vec![(Variation::Linear, 0.25), (Variation::Spherical, 0.75)],
+            ..Transform::new([2.0, 0.0, 1.0, 0.0, 2.0, -1.0], 1.0, 0.0)
+        };
+        // The affine part takes (0.5, 1.5) to (2, 2), which spherical takes to (0.25, 0.25)
+        let out = transform.apply(0.5, 1.5, &mut rng);
+        assert!(close(out, (0.25*2.0 + 0.75*0.25, 0.25*2.0 + 0.75*0.25)), "{:?}", out);
+    }
+
+    #[test]
+    fn transforms_are_picked_by_weight() {
+        let flame = Flame::new(vec![
+            Transform::new([1.0, 0.0, 0.0, 0.0, 1.0, 0.0], 1.0, 0.0),
+            Transform::new([1.0, 0.0, 0.0, 0.0, 1.0, 0.0], 3.0, 1.0),
+        ], 0);
+        assert_eq!(flame.pick(0.0).color, 0.0);
+        assert_eq!(flame.pick(0.24).color, 0.0);
+        assert_eq!(flame.pick(0.26).color, 1.0);
+        assert_eq!(flame.pick(0.999).color, 1.0);
+    }
+
+    #[test]
+    fn sierpinski_stays_in_its_triangle() {
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0xffffff, 0.0);
+        // The triangle is (0, 0), (1, 0) and (0, 1), so the bottom right
+        // half of this view should stay dark
+        let ctx = RenderingContext { x: 0.5.into(), y: 0.5.into(), scale: 1.0, max_iter: 0, x_px: 64, y_px: 64, precision: Precision::F64, strategy: Strategy::EveryPixel };
+        let img = render_flame_rgb(ctx, &cs, &Flame::sierpinski(100000), &Monitor::new()).unwrap();
+        let mut lit = 0;
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if pixel.data == [0, 0, 0] { continue; }
+            lit += 1;
+            assert!(x + y <= 64, "({}, {}) is outside the triangle", x, y);
+        }
+        assert!(lit > 64*64/8);
+    }
+
+    #[test]
+    fn flames_need_weighted_transforms() {
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0xffffff, 0.0);
+        let ctx = RenderingContext { x: 0.5.into(), y: 0.5.into(), scale: 1.0, max_iter: 0, x_px: 8, y_px: 8, precision: Precision::F64, strategy: Strategy::EveryPixel };
+        let identity = |weight| Transform::new([1.0, 0.0, 0.0, 0.0, 1.0, 0.0], weight, 0.0);
+
+        let empty = Flame::new(Vec::new(), 1000);
+        assert!(matches!(render_flame_rgb(ctx.clone(), &cs, &empty, &Monitor::new()), Err(Error::EmptyFlame)));
+        let weightless = Flame::new(vec![identity(0.0), identity(0.0)], 1000);
+        assert!(matches!(weightless.validate(), Err(Error::EmptyFlame)));
+        let negative = Flame::new(vec![identity(2.0), identity(-1.0)], 1000);
+        assert!(matches!(render_flame_rgb(ctx, &cs, &negative, &Monitor::new()), Err(Error::TransformWeight(weight)) if weight == -1.0));
+        let nan = Flame::new(vec![identity(f64::NAN)], 1000);
+        assert!(matches!(nan.validate(), Err(Error::TransformWeight(weight)) if weight.is_nan()));
+
+        assert!(Flame::new(vec![identity(0.0), identity(1.0)], 1000).validate().is_ok());
+        assert!(Flame::sierpinski(1000).validate().is_ok());
+    }
+}
@@ -0,0 +1,977 @@
+//! Built-in escape time formulas.
+//!
+//! Every formula implements `Fractal`, so it can be handed straight to
+//! `render_image`, `render_animation` or `render_vfr`. All of them except
+//! `SineJulia` and non-integer `Multibrot` powers are iterated in whichever
+//! `Precision` the context asks for.
+//!
+//! ```
+//! # extern crate fractal;
+//! # use fractal::Fractal;
+//! # use fractal::formulas::{Mandelbrot, Julia, Multibrot, BurningShip, Newton, MagnetI};
+//! # fn main() {
+//! assert_eq!(Mandelbrot.iterate(0.0, 0.0, 100, 0.0), 100);
+//! assert_eq!(Mandelbrot.iterate(-1.0, 0.0, 100, 0.0), 100);
+//! assert_eq!(Mandelbrot.iterate(1.0, 1.0, 100, 0.0), 2);
+//! assert_eq!(BurningShip.iterate(-0.5, 0.0, 100, 0.0), 100);
+//! assert_eq!(Julia::new(0.0, 0.0).iterate(0.5, 0.5, 100, 0.0), 100);
+//! assert_eq!(Multibrot::new(2.0).iterate(0.3, 0.1, 100, 0.0), Mandelbrot.iterate(0.3, 0.1, 100, 0.0));
+//! assert_eq!(Mandelbrot.escape(-1.0, 0.0, 100, 0.0).period, Some(2));
+//! assert_eq!(Mandelbrot.escape(-0.12, 0.75, 1000, 0.0).period, Some(3));
+//!
+//! let cubic = Newton::from_coeffs(&[(-1.0, 0.0), (0.0, 0.0), (0.0, 0.0), (1.0, 0.0)]);
+//! let root = cubic.escape(0.9, 0.1, 100, 0.0).root.unwrap();
+//! assert!((cubic.roots[root].re - 1.0).abs() < 1e-9);
+//!
+//! // Converges straight onto the fixed point at 1
+//! assert_eq!(MagnetI.escape(1.5, 0.0, 100, 0.0).z, (1.0, 0.0));
+//! # }
+//! ```
+
+use super::{Fractal, Stateful, Bailout, RenderingContext, EscapeResult, Precision, Strategy, Real, Complex};
+use super::fractal::{normalized, SMOOTH_BAILOUT};
+
+fn result<T: Real>(iter: u64, z: &Complex<T>, dz: Option<Complex<f64>>, power: f64, max_iter: u64) -> EscapeResult {
+    let (x, y) = z.to_f64();
+    if iter >= max_iter { return EscapeResult::interior(max_iter, (x, y)) }
+    let mut result = EscapeResult::escaped(iter, normalized(iter, x*x + y*y, SMOOTH_BAILOUT, power, max_iter), (x, y));
+    result.derivative = dz.map(|dz| (dz.re, dz.im));
+    result
+}
+
+/// Squared distance within which an orbit counts as having come back around.
+const CYCLE_TOLERANCE: f64 = 1e-16;
+
+/// Fills in the period of the cycle of `z = z^2 + c` an interior point's
+/// final `z` has settled into. With `distance` set the interior distance
+/// estimate is worked out too, which only means anything for the Mandelbrot
+/// set since it is measured in `c`. Cycles longer than a sixteenth of
+/// `max_iter` are given up on, which keeps the search to a few percent of
+/// the time spent on the point.
+fn quadratic_interior(result: &mut EscapeResult, c: Complex<f64>, max_iter: u64, distance: bool) {
+    let z0 = Complex::new(result.z.0, result.z.1);
+    let mut z = z0;
+    for period in 1..=(max_iter / 16).max(1) {
+        z = z.square() + c;
+        if (z - z0).norm_sqr() < CYCLE_TOLERANCE {
+            result.period = Some(period);
+            if distance { result.interior_distance = interior_distance(z0, c, period) }
+            return;
+        }
+    }
+}
+
+/// How many units of the last place an orbit has to come back within for
+/// periodicity checking to call it a cycle.
+const PERIOD_ULPS: f64 = 1024.0;
+
+/// The period of the main cardioid or period 2 bulb of the Mandelbrot set if
+/// `c` is in either, both of which are worked out exactly.
+pub(crate) fn in_bulb<T: Real>(c: &Complex<T>) -> Option<u64> {
+    let quarter = T::from_f64(0.25);
+    let (x, y) = (c.re.clone(), c.im.clone());
+    let y2 = y.clone()*y;
+    let shifted = x.clone() - quarter.clone();
+    let q = shifted.clone()*shifted.clone() + y2.clone();
+    if q.clone()*(q + shifted) <= quarter*y2.clone() { return Some(1) }
+    let shifted = x + T::from_f64(1.0);
+    if shifted.clone()*shifted + y2 <= T::from_f64(0.0625) { return Some(2) }
+    None
+}
+
+/// Distance from `c` to the edge of the hyperbolic component it is in, given
+/// a point `z0` on its attracting cycle of length `period`.
+fn interior_distance(z0: Complex<f64>, c: Complex<f64>, period: u64) -> Option<f64> {
+    let one = Complex::new(1.0, 0.0);
+    let two = Complex::new(2.0, 0.0);
+    let mut z = z0;
+    let mut dz = one;
+    let mut dc = Complex::new(0.0, 0.0);
+    let mut dzdz = Complex::new(0.0, 0.0);
+    let mut dcdz = Complex::new(0.0, 0.0);
+    for _ in 0..period {
+        dcdz = two*(z*dcdz + dc*dz);
+        dzdz = two*(dz*dz + z*dzdz);
+        dc = two*z*dc + one;
+        dz = two*z*dz;
+        z = z.square() + c;
+    }
+    let multiplier = dz.norm_sqr();
+    if multiplier >= 1.0 { return None }
+    let denominator = dcdz + dzdz*dc/(one - dz);
+    Some((1.0 - multiplier) / denominator.norm_sqr().sqrt())
+}
+
+fn context(x: f64, y: f64, scale: f64) -> RenderingContext {
+    RenderingContext {
+        x: x.into(), y: y.into(),
+        scale, max_iter: 256,
+        x_px: 512, y_px: 512,
+        precision: Precision::F64,
+        strategy: Strategy::EveryPixel,
+    }
+}
+
+/// `z = z^2 + c`, starting from `z = 0`.
+#[derive(Clone, Copy)]
+pub struct Mandelbrot;
+
+impl Mandelbrot {
+    /// Also returns `dz/dc` if `track` is set, which is done in `f64` since
+    /// only its magnitude is ever needed. `visit` is called with every `z`.
+    ///
+    /// The orbit is checked for cycles Brent style: every `z` is compared
+    /// against a saved one, which jumps ahead to the current `z` after 1, 2,
+    /// 4, 8... iterations. Coming back to the saved `z` means the orbit will
+    /// never escape, and how long that took is the period of the cycle.
+    fn orbit<T: Real, V: FnMut(&Complex<T>)>(c: &Complex<T>, max_iter: u64, bailout: f64, track: bool, mut visit: V) -> (u64, Complex<T>, Complex<f64>, Option<u64>) {
+        let mut z : Complex<T> = Complex::from_f64(0.0, 0.0);
+        let mut dz = Complex::from_f64(0.0, 0.0);
+        let mut iter = 0;
+
+        let tolerance = c.re.epsilon()*PERIOD_ULPS;
+        // Comparing in f64 first is much cheaper than in T, and only off by
+        // the rounding of each side
+        let rough = (tolerance + 4.0*f64::EPSILON).powi(2);
+        let tolerance = tolerance.powi(2);
+        let mut saved = z.clone();
+        let mut saved_f64 = (0.0, 0.0);
+        let mut saved_iter = 0;
+        let mut interval = 1;
+        // z rounded to f64, shared by the derivative and the cycle check
+        let (mut x, mut y) = (0.0, 0.0);
+
+        while z.norm_sqr().to_f64() < bailout && iter < max_iter {
+            if track {
+                dz = Complex::new(2.0*x, 2.0*y)*dz + Complex::from_f64(1.0, 0.0);
+            }
+            z = z.square() + c.clone();
+            visit(&z);
+            iter += 1;
+
+            let rounded = z.to_f64();
+            x = rounded.0;
+            y = rounded.1;
+            let near = (x - saved_f64.0).powi(2) + (y - saved_f64.1).powi(2) <= rough;
+            if near && (z.clone() - saved.clone()).norm_sqr().to_f64() <= tolerance {
+                return (max_iter, z, dz, Some(iter - saved_iter));
+            }
+            if iter - saved_iter == interval {
+                saved = z.clone();
+                saved_f64 = (x, y);
+                saved_iter = iter;
+                interval *= 2;
+            }
+        }
+
+        (iter, z, dz, None)
+    }
+
+    fn escape_with<T: Real, V: FnMut(&Complex<T>)>(c: Complex<T>, max_iter: u64, visit: V) -> EscapeResult {
+        let (x, y) = c.to_f64();
+        let c64 = Complex::new(x, y);
+        // Both of these are known exactly, so the orbit can be skipped
+        if let Some(period) = in_bulb(&c) {
+            let one = Complex::new(1.0, 0.0);
+            let four = Complex::new(4.0, 0.0);
+            let z = if period == 1 {
+                (one - (one - four*c64).sqrt())*Complex::new(0.5, 0.0)
+            } else {
+                ((Complex::new(-3.0, 0.0) - four*c64).sqrt() - one)*Complex::new(0.5, 0.0)
+            };
+            let mut result = EscapeResult::interior(max_iter, (z.re, z.im));
+            result.period = Some(period);
+            result.interior_distance = interior_distance(z, c64, period);
+            return result;
+        }
+
+        let (iter, z, dz, period) = Mandelbrot::orbit(&c, max_iter, SMOOTH_BAILOUT, true, visit);
+        let mut result = result(iter, &z, Some(dz), 2.0, max_iter);
+        if let Some(period) = period {
+            result.period = Some(period);
+            result.interior_distance = interior_distance(Complex::new(result.z.0, result.z.1), c64, period);
+        }
+        result
+    }
+}
+
+impl Fractal for Mandelbrot {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        let c = Complex::new(x0, y0);
+        if in_bulb(&c).is_some() { return max_iter }
+        Mandelbrot::orbit(&c, max_iter, 4.0, false, |_| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_precise(&x0, &y0, max_iter, t)
+    }
+
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, _t: f64) -> EscapeResult {
+        Mandelbrot::escape_with(Complex::new(x0.clone(), y0.clone()), max_iter, |_| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        Mandelbrot::escape_with(Complex::new(x0, y0), max_iter, |z| visit(z.re, z.im))
+    }
+
+    fn name(&self) -> &str { "mandelbrot" }
+
+    fn default_context(&self) -> RenderingContext { context(-0.5, 0.0, 3.5) }
+}
+
+/// `z = z^2 + c` for a fixed `c`, starting from the pixel.
+#[derive(Clone, Copy)]
+pub struct Julia {
+    pub cx: f64,
+    pub cy: f64,
+}
+
+impl Julia {
+    pub fn new(cx: f64, cy: f64) -> Julia {
+        Julia { cx, cy }
+    }
+
+    /// Also returns `dz/dz0` if `track` is set. `visit` is called with every `z`.
+    fn orbit<T: Real, V: FnMut(&Complex<T>)>(&self, z0: Complex<T>, max_iter: u64, bailout: f64, track: bool, mut visit: V) -> (u64, Complex<T>, Complex<f64>) {
+        let c = Complex::from_f64(self.cx, self.cy);
+        let mut z = z0;
+        let mut dz = Complex::from_f64(1.0, 0.0);
+        let mut iter = 0;
+
+        while z.norm_sqr().to_f64() < bailout && iter < max_iter {
+            if track {
+                let (x, y) = z.to_f64();
+                dz = Complex::new(2.0*x, 2.0*y)*dz;
+            }
+            z = z.square() + c.clone();
+            visit(&z);
+            iter += 1;
+        }
+
+        (iter, z, dz)
+    }
+
+    fn escape_with<T: Real, V: FnMut(&Complex<T>)>(&self, z0: Complex<T>, max_iter: u64, visit: V) -> EscapeResult {
+        let (iter, z, dz) = self.orbit(z0, max_iter, SMOOTH_BAILOUT, true, visit);
+        let mut result = result(iter, &z, Some(dz), 2.0, max_iter);
+        if !result.escaped { quadratic_interior(&mut result, Complex::new(self.cx, self.cy), max_iter, false) }
+        result
+    }
+}
+
+impl Fractal for Julia {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        self.orbit(Complex::new(x0, y0), max_iter, 4.0, false, |_| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_precise(&x0, &y0, max_iter, t)
+    }
+
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, _t: f64) -> EscapeResult {
+        self.escape_with(Complex::new(x0.clone(), y0.clone()), max_iter, |_| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.escape_with(Complex::new(x0, y0), max_iter, |z| visit(z.re, z.im))
+    }
+
+    fn name(&self) -> &str { "julia" }
+
+    fn default_context(&self) -> RenderingContext { context(0.0, 0.0, 3.5) }
+}
+
+/// `z = (|Re z| + i|Im z|)^2 + c`, starting from `z = 0`.
+#[derive(Clone, Copy)]
+pub struct BurningShip;
+
+impl BurningShip {
+    fn orbit<T: Real, V: FnMut(&Complex<T>)>(c: &Complex<T>, max_iter: u64, bailout: f64, mut visit: V) -> (u64, Complex<T>) {
+        let mut z : Complex<T> = Complex::from_f64(0.0, 0.0);
+        let mut iter = 0;
+
+        while z.norm_sqr().to_f64() < bailout && iter < max_iter {
+            z = Complex::new(z.re.abs(), z.im.abs()).square() + c.clone();
+            visit(&z);
+            iter += 1;
+        }
+
+        (iter, z)
+    }
+}
+
+impl Fractal for BurningShip {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        BurningShip::orbit(&Complex::new(x0, y0), max_iter, 4.0, |_| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_precise(&x0, &y0, max_iter, t)
+    }
+
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, _t: f64) -> EscapeResult {
+        let (iter, z) = BurningShip::orbit(&Complex::new(x0.clone(), y0.clone()), max_iter, SMOOTH_BAILOUT, |_| {});
+        result(iter, &z, None, 2.0, max_iter)
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let (iter, z) = BurningShip::orbit(&Complex::new(x0, y0), max_iter, SMOOTH_BAILOUT, |z| visit(z.re, z.im));
+        result(iter, &z, None, 2.0, max_iter)
+    }
+
+    fn name(&self) -> &str { "burning ship" }
+
+    fn default_context(&self) -> RenderingContext { context(-0.4, -0.5, 3.5) }
+}
+
+/// `z = conj(z)^2 + c`, starting from `z = 0`. Also known as the Mandelbar set.
+#[derive(Clone, Copy)]
+pub struct Tricorn;
+
+impl Tricorn {
+    fn orbit<T: Real, V: FnMut(&Complex<T>)>(c: &Complex<T>, max_iter: u64, bailout: f64, mut visit: V) -> (u64, Complex<T>) {
+        let mut z : Complex<T> = Complex::from_f64(0.0, 0.0);
+        let mut iter = 0;
+
+        while z.norm_sqr().to_f64() < bailout && iter < max_iter {
+            z = z.conj().square() + c.clone();
+            visit(&z);
+            iter += 1;
+        }
+
+        (iter, z)
+    }
+}
+
+impl Fractal for Tricorn {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        Tricorn::orbit(&Complex::new(x0, y0), max_iter, 4.0, |_| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_precise(&x0, &y0, max_iter, t)
+    }
+
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, _t: f64) -> EscapeResult {
+        let (iter, z) = Tricorn::orbit(&Complex::new(x0.clone(), y0.clone()), max_iter, SMOOTH_BAILOUT, |_| {});
+        result(iter, &z, None, 2.0, max_iter)
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let (iter, z) = Tricorn::orbit(&Complex::new(x0, y0), max_iter, SMOOTH_BAILOUT, |z| visit(z.re, z.im));
+        result(iter, &z, None, 2.0, max_iter)
+    }
+
+    fn name(&self) -> &str { "tricorn" }
+
+    fn default_context(&self) -> RenderingContext { context(-0.3, 0.0, 4.0) }
+}
+
+/// `z = z^power + c`, starting from `z = 0`. The power does not need to be an integer.
+#[derive(Clone, Copy)]
+pub struct Multibrot {
+    pub power: f64,
+}
+
+impl Multibrot {
+    pub fn new(power: f64) -> Multibrot {
+        Multibrot { power }
+    }
+
+    /// Non-integer powers go through polar form, which is only done in `f64`.
+    /// Also returns `dz/dc` if `track` is set, which only works for integer
+    /// powers. `visit` is called with every `z`.
+    fn orbit<T: Real, V: FnMut(&Complex<T>)>(&self, c: &Complex<T>, max_iter: u64, bailout: f64, track: bool, mut visit: V) -> (u64, Complex<T>, Option<Complex<f64>>) {
+        let mut z : Complex<T> = Complex::from_f64(0.0, 0.0);
+        let mut dz = Complex::from_f64(0.0, 0.0);
+        let mut iter = 0;
+
+        // Integer powers are done by repeated multiplication, which is both
+        // faster and more accurate than going through polar form
+        let int_power = if self.power.fract() == 0.0 && self.power >= 1.0 { Some(self.power as u32) } else { None };
+
+        while z.norm_sqr().to_f64() < bailout && iter < max_iter {
+            let power = match int_power {
+                Some(n) => {
+                    let mut p = z.clone();
+                    for _ in 1..n {
+                        p = p * z.clone();
+                    }
+                    if track {
+                        // dz' = n*z^(n-1)*dz + 1
+                        let (x, y) = z.to_f64();
+                        let mut d = Complex::from_f64(n as f64, 0.0);
+                        for _ in 1..n {
+                            d = d * Complex::new(x, y);
+                        }
+                        dz = d*dz + Complex::from_f64(1.0, 0.0);
+                    }
+                    p
+                },
+                None => {
+                    let (x, y) = z.to_f64();
+                    if x == 0.0 && y == 0.0 {
+                        Complex::from_f64(0.0, 0.0)
+                    } else {
+                        let r = (x*x + y*y).powf(self.power/2.0);
+                        let theta = y.atan2(x)*self.power;
+                        Complex::from_f64(r*theta.cos(), r*theta.sin())
+                    }
+                },
+            };
+            z = power + c.clone();
+            visit(&z);
+            iter += 1;
+        }
+
+        (iter, z, int_power.filter(|_| track).map(|_| dz))
+    }
+
+    fn escape_with<T: Real, V: FnMut(&Complex<T>)>(&self, c: Complex<T>, max_iter: u64, visit: V) -> EscapeResult {
+        let (iter, z, dz) = self.orbit(&c, max_iter, SMOOTH_BAILOUT, true, visit);
+        // Powers at or below 1 don't escape in a way that can be normalized
+        if self.power <= 1.0 && iter < max_iter { return EscapeResult::escaped(iter, iter as f64, z.to_f64()) }
+        result(iter, &z, dz, self.power, max_iter)
+    }
+}
+
+impl Fractal for Multibrot {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        self.orbit(&Complex::new(x0, y0), max_iter, 4.0, false, |_| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_precise(&x0, &y0, max_iter, t)
+    }
+
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, _t: f64) -> EscapeResult {
+        self.escape_with(Complex::new(x0.clone(), y0.clone()), max_iter, |_| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.escape_with(Complex::new(x0, y0), max_iter, |z| visit(z.re, z.im))
+    }
+
+    fn name(&self) -> &str { "multibrot" }
+
+    fn default_context(&self) -> RenderingContext { context(0.0, 0.0, 3.5) }
+}
+
+/// `z = c*sin(z)` for a fixed `c`, starting from the pixel.
+#[derive(Clone, Copy)]
+pub struct SineJulia {
+    pub cx: f64,
+    pub cy: f64,
+}
+
+impl SineJulia {
+    pub fn new(cx: f64, cy: f64) -> SineJulia {
+        SineJulia { cx, cy }
+    }
+
+    fn orbit<V: FnMut(f64, f64)>(&self, x0: f64, y0: f64, max_iter: u64, mut visit: V) -> (u64, f64, f64) {
+        let mut x = x0;
+        let mut y = y0;
+        let mut iter = 0;
+
+        while y.abs() < 50.0 && iter < max_iter {
+            let xtemp = x.sin()*y.cosh();
+            let ytemp = x.cos()*y.sinh();
+            x = self.cx*xtemp - self.cy*ytemp;
+            y = self.cx*ytemp + self.cy*xtemp;
+            visit(x, y);
+            iter += 1;
+        }
+
+        (iter, x, y)
+    }
+}
+
+impl Fractal for SineJulia {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> u64 {
+        self.orbit(x0, y0, max_iter, |_, _| {}).0
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_visit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, _t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let (iter, x, y) = self.orbit(x0, y0, max_iter, visit);
+        if iter >= max_iter { return EscapeResult::interior(max_iter, (x, y)) }
+        EscapeResult::escaped(iter, iter as f64, (x, y))
+    }
+
+    fn name(&self) -> &str { "sine julia" }
+
+    fn default_context(&self) -> RenderingContext { context(0.0, 0.0, 12.0) }
+}
+
+/// How close `z` has to get to a root to count as converged.
+const NEWTON_TOLERANCE: f64 = 1e-6;
+
+/// `p(z)` and `p'(z)` for coefficients given lowest degree first.
+fn polynomial(coeffs: &[Complex<f64>], z: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+    let mut p = Complex::from_f64(0.0, 0.0);
+    let mut dp = Complex::from_f64(0.0, 0.0);
+    for &coeff in coeffs.iter().rev() {
+        dp = dp*z + p;
+        p = p*z + coeff;
+    }
+    (p, dp)
+}
+
+/// Finds every root at once with the Durand-Kerner method.
+fn find_roots(coeffs: &[Complex<f64>]) -> Vec<Complex<f64>> {
+    let degree = coeffs.len() - 1;
+    let lead = coeffs[degree];
+    let monic : Vec<Complex<f64>> = coeffs.iter().map(|&c| c / lead).collect();
+
+    // Starting points just need to be distinct and not symmetric
+    let seed = Complex::from_f64(0.4, 0.9);
+    let mut roots = vec![Complex::from_f64(1.0, 0.0); degree];
+    for i in 1..degree {
+        roots[i] = roots[i - 1]*seed;
+    }
+
+    for _ in 0..1000 {
+        let mut change : f64 = 0.0;
+        for i in 0..degree {
+            let mut denom = Complex::from_f64(1.0, 0.0);
+            for j in 0..degree {
+                if i != j { denom = denom*(roots[i] - roots[j]); }
+            }
+            let delta = polynomial(&monic, roots[i]).0 / denom;
+            roots[i] = roots[i] - delta;
+            change = change.max(delta.norm_sqr());
+        }
+        if change < 1e-28 { break; }
+    }
+    roots
+}
+
+/// Newton's method on a polynomial, `z = z - a*p(z)/p'(z)`, starting from the
+/// pixel. Meant to be colored with `Coloring::Root`, which shows the basin of
+/// each root.
+///
+/// The Nova variant adds the pixel as `c` every step and starts from `z = 1`
+/// instead. It settles on points that aren't roots, so it is colored by how
+/// long it took to converge or, with a large enough relaxation, to escape.
+#[derive(Clone)]
+pub struct Newton {
+    /// Coefficients of `p`, lowest degree first
+    pub coeffs: Vec<Complex<f64>>,
+    pub roots: Vec<Complex<f64>>,
+    /// The relaxation `a`
+    pub relaxation: Complex<f64>,
+    pub nova: bool,
+}
+
+impl Newton {
+    /// The polynomial with exactly these roots.
+    pub fn from_roots(roots: &[(f64, f64)]) -> Newton {
+        let mut coeffs = vec![Complex::from_f64(1.0, 0.0)];
+        for &(x, y) in roots {
+            // Multiply by (z - root)
+            let root = Complex::from_f64(x, y);
+            let mut next = vec![Complex::from_f64(0.0, 0.0); coeffs.len() + 1];
+            for (i, &coeff) in coeffs.iter().enumerate() {
+                next[i + 1] = next[i + 1] + coeff;
+                next[i] = next[i] - coeff*root;
+            }
+            coeffs = next;
+        }
+        Newton {
+            coeffs,
+            roots: roots.iter().map(|&(x, y)| Complex::from_f64(x, y)).collect(),
+            relaxation: Complex::from_f64(1.0, 0.0),
+            nova: false,
+        }
+    }
+
+    /// The polynomial with these coefficients, lowest degree first. Its roots
+    /// are found numerically.
+    pub fn from_coeffs(coeffs: &[(f64, f64)]) -> Newton {
+        let mut coeffs : Vec<Complex<f64>> = coeffs.iter().map(|&(x, y)| Complex::from_f64(x, y)).collect();
+        while coeffs.len() > 1 && *coeffs.last().unwrap() == Complex::from_f64(0.0, 0.0) {
+            coeffs.pop();
+        }
+        Newton {
+            roots: if coeffs.len() > 1 { find_roots(&coeffs) } else { Vec::new() },
+            coeffs,
+            relaxation: Complex::from_f64(1.0, 0.0),
+            nova: false,
+        }
+    }
+
+    pub fn nova(self) -> Newton {
+        Newton { nova: true, ..self }
+    }
+
+    /// Index of and distance to the root closest to `z`.
+    fn nearest_root(&self, z: Complex<f64>) -> (Option<usize>, f64) {
+        self.roots.iter().enumerate()
+            .map(|(i, &root)| (Some(i), (z - root).norm()))
+            .fold((None, f64::INFINITY), |a, b| if b.1 < a.1 { b } else { a })
+    }
+}
+
+impl Stateful for Newton {
+    type State = ();
+
+    fn start(&self, c: Complex<f64>, _t: f64) -> ((), Complex<f64>) {
+        ((), if self.nova { Complex::from_f64(1.0, 0.0) } else { c })
+    }
+
+    fn step(&self, _state: &mut (), z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let (p, dp) = polynomial(&self.coeffs, z);
+        let next = z - self.relaxation*(p / dp);
+        if self.nova { next + c } else { next }
+    }
+
+    /// Nova can also fly off when the relaxation is large, Newton can't.
+    fn bailout(&self) -> Bailout {
+        Bailout {
+            divergence: if self.nova { Some(SMOOTH_BAILOUT) } else { None },
+            convergence: Some(NEWTON_TOLERANCE),
+        }
+    }
+
+    /// Far from the roots each step only scales `z`.
+    fn power(&self) -> f64 { 1.0 }
+
+    /// Nova doesn't head for the roots, so only the step size is watched.
+    fn distance(&self, z: Complex<f64>, next: Complex<f64>) -> f64 {
+        if self.nova { (next - z).norm() } else { self.nearest_root(next).1 }
+    }
+
+    fn root(&self, z: Complex<f64>) -> Option<usize> {
+        if self.nova { None } else { self.nearest_root(z).0 }
+    }
+}
+
+impl Fractal for Newton {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {}).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, visit)
+    }
+
+    fn name(&self) -> &str { if self.nova { "nova" } else { "newton" } }
+
+    fn default_context(&self) -> RenderingContext { context(0.0, 0.0, 4.0) }
+}
+
+/// The Phoenix Julia set, `z = z^2 + c + p*z'` where `z'` is the `z` before
+/// the current one, starting from the pixel.
+#[derive(Clone, Copy)]
+pub struct Phoenix {
+    pub c: Complex<f64>,
+    pub p: Complex<f64>,
+}
+
+impl Phoenix {
+    pub fn new(c: (f64, f64), p: (f64, f64)) -> Phoenix {
+        Phoenix { c: Complex::new(c.0, c.1), p: Complex::new(p.0, p.1) }
+    }
+}
+
+impl Stateful for Phoenix {
+    /// The previous `z`
+    type State = Complex<f64>;
+
+    fn start(&self, c: Complex<f64>, _t: f64) -> (Complex<f64>, Complex<f64>) {
+        (Complex::new(0.0, 0.0), c)
+    }
+
+    fn step(&self, previous: &mut Complex<f64>, z: Complex<f64>, _c: Complex<f64>) -> Complex<f64> {
+        let next = z.square() + self.c + self.p**previous;
+        *previous = z;
+        next
+    }
+
+    fn bailout(&self) -> Bailout {
+        Bailout { divergence: Some(SMOOTH_BAILOUT), convergence: None }
+    }
+}
+
+impl Fractal for Phoenix {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {}).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, visit)
+    }
+
+    fn name(&self) -> &str { "phoenix" }
+
+    fn default_context(&self) -> RenderingContext { context(0.0, 0.0, 3.5) }
+}
+
+/// How far out magnet orbits count as escaped.
+const MAGNET_BAILOUT: f64 = 10000.0;
+
+/// How small a step magnet orbits have to take to count as converged.
+const MAGNET_TOLERANCE: f64 = 1e-9;
+
+/// Magnet type I, `z = ((z^2 + c - 1)/(2z + c - 2))^2` starting from
+/// `z = 0`. Orbits either escape or converge, most of them to `z = 1`.
+#[derive(Clone, Copy)]
+pub struct MagnetI;
+
+impl Stateful for MagnetI {
+    type State = ();
+
+    fn start(&self, _c: Complex<f64>, _t: f64) -> ((), Complex<f64>) {
+        ((), Complex::new(0.0, 0.0))
+    }
+
+    fn step(&self, _state: &mut (), z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let one = Complex::new(1.0, 0.0);
+        let two = Complex::new(2.0, 0.0);
+        ((z.square() + c - one) / (two*z + c - two)).square()
+    }
+
+    fn bailout(&self) -> Bailout {
+        Bailout { divergence: Some(MAGNET_BAILOUT), convergence: Some(MAGNET_TOLERANCE) }
+    }
+}
+
+impl Fractal for MagnetI {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {}).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, visit)
+    }
+
+    fn name(&self) -> &str { "magnet i" }
+
+    fn default_context(&self) -> RenderingContext { context(1.0, 0.0, 6.0) }
+}
+
+/// Magnet type II,
+/// `z = ((z^3 + 3(c - 1)z + (c - 1)(c - 2))/(3z^2 + 3(c - 2)z + (c - 1)(c - 2) + 1))^2`
+/// starting from `z = 0`.
+#[derive(Clone, Copy)]
+pub struct MagnetII;
+
+impl Stateful for MagnetII {
+    type State = ();
+
+    fn start(&self, _c: Complex<f64>, _t: f64) -> ((), Complex<f64>) {
+        ((), Complex::new(0.0, 0.0))
+    }
+
+    fn step(&self, _state: &mut (), z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let one = Complex::new(1.0, 0.0);
+        let three = Complex::new(3.0, 0.0);
+        let (c1, c2) = (c - one, c - Complex::new(2.0, 0.0));
+        let numerator = z.square()*z + three*c1*z + c1*c2;
+        let denominator = three*z.square() + three*c2*z + c1*c2 + one;
+        (numerator / denominator).square()
+    }
+
+    fn bailout(&self) -> Bailout {
+        Bailout { divergence: Some(MAGNET_BAILOUT), convergence: Some(MAGNET_TOLERANCE) }
+    }
+}
+
+impl Fractal for MagnetII {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {}).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, &mut |_, _| {})
+    }
+
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.orbit(x0, y0, max_iter, t, visit)
+    }
+
+    fn name(&self) -> &str { "magnet ii" }
+
+    fn default_context(&self) -> RenderingContext { context(1.0, 0.0, 6.0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mandelbrot, Julia, BurningShip, Tricorn, Multibrot, SineJulia};
+    use super::super::{Fractal, EscapeResult, DoubleDouble, Complex};
+
+    fn smooth(result: EscapeResult, iter: u64, smooth: f64) {
+        assert!(result.escaped);
+        assert_eq!(result.iter, iter);
+        assert!((result.smooth - smooth).abs() < 1e-9, "smooth {} isn't {}", result.smooth, smooth);
+    }
+
+    #[test]
+    fn escape_counts() {
+        assert_eq!(Mandelbrot.iterate(0.5, 0.0, 100, 0.0), 5);
+        assert_eq!(Mandelbrot.iterate(-0.75, 0.1, 100, 0.0), 33);
+        assert_eq!(Julia::new(-0.8, 0.156).iterate(0.5, 0.5, 100, 0.0), 4);
+        assert_eq!(Julia::new(0.0, 0.0).iterate(1.5, 0.0, 100, 0.0), 1);
+        assert_eq!(BurningShip.iterate(1.0, 0.0, 100, 0.0), 2);
+        assert_eq!(BurningShip.iterate(0.0, -2.0, 100, 0.0), 1);
+        assert_eq!(Multibrot::new(3.0).iterate(0.5, 0.0, 100, 0.0), 6);
+        // z = i, -1 + i, then 3i rather than the Mandelbrot set's cycle
+        assert_eq!(Tricorn.iterate(0.0, 1.0, 100, 0.0), 3);
+        assert_eq!(Mandelbrot.iterate(0.0, 1.0, 100, 0.0), 100);
+        // sin(5i) = i sinh(5), which is past the bailout of 50
+        assert_eq!(SineJulia::new(1.0, 0.0).iterate(0.0, 5.0, 100, 0.0), 1);
+        assert_eq!(SineJulia::new(1.0, 0.0).iterate(0.0, 3.0, 100, 0.0), 2);
+    }
+
+    #[test]
+    fn points_that_never_escape() {
+        assert_eq!(Julia::new(0.0, 0.0).iterate(0.9, 0.3, 100, 0.0), 100);
+        assert_eq!(BurningShip.iterate(0.0, -1.0, 100, 0.0), 100);
+        assert_eq!(Tricorn.iterate(-0.2, 0.1, 100, 0.0), 100);
+        assert_eq!(Multibrot::new(3.0).iterate(0.0, 0.5, 100, 0.0), 100);
+        // A real c keeps z real, where c*sin(z) stays between -1 and 1
+        let sine = SineJulia::new(1.0, 0.0);
+        assert_eq!(sine.iterate(2.0, 0.0, 100, 0.0), 100);
+        assert!(!sine.escape(2.0, 0.0, 100, 0.0).escaped);
+    }
+
+    #[test]
+    fn smooth_values() {
+        smooth(Mandelbrot.escape(0.5, 0.0, 100, 0.0), 8, 8.239629476078015);
+        smooth(Mandelbrot.escape(-0.75, 0.1, 100, 0.0), 36, 36.42456786056252);
+        smooth(Julia::new(-0.8, 0.156).escape(0.5, 0.5, 100, 0.0), 7, 7.30085687033411);
+        smooth(BurningShip.escape(1.0, 0.0, 100, 0.0), 5, 5.766877037313519);
+        smooth(Tricorn.escape(0.0, 1.0, 100, 0.0), 6, 6.330641521347579);
+        smooth(Multibrot::new(3.0).escape(0.5, 0.0, 100, 0.0), 8, 8.630561424918689);
+        // Sine Julia sets aren't normalized
+        smooth(SineJulia::new(1.0, 0.0).escape(0.0, 3.0, 100, 0.0), 2, 2.0);
+    }
+
+    #[test]
+    fn smooth_values_are_continuous() {
+        // With c = 0 every |z0| > 1 escapes to exactly 4 - log2(log2 |z0|),
+        // however many iterations that takes
+        let julia = Julia::new(0.0, 0.0);
+        for &r in &[1.01, 1.1, 1.5, 2.0, 10.0, 200.0] {
+            for &angle in &[0.0, 1.0, 2.5, -2.0] {
+                let (x, y) = (r*f64::cos(angle), r*f64::sin(angle));
+                let result = julia.escape(x, y, 1000, 0.0);
+                assert!((result.smooth - (4.0 - r.log2().log2())).abs() < 1e-9, "r = {}", r);
+            }
+        }
+
+        // Walking out along the real axis the count drops one at a time,
+        // but the smooth value never jumps
+        let fractals : [(&str, &dyn Fn(f64) -> EscapeResult); 4] = [
+            ("mandelbrot", &|x| Mandelbrot.escape(x, 0.0, 1000, 0.0)),
+            ("tricorn", &|x| Tricorn.escape(x, 0.0, 1000, 0.0)),
+            ("burning ship", &|x| BurningShip.escape(x, 0.0, 1000, 0.0)),
+            ("multibrot", &|x| Multibrot::new(3.0).escape(x, 0.0, 1000, 0.0)),
+        ];
+        for &(name, escape) in &fractals {
+            let mut last = escape(0.5);
+            for step in 1..2000 {
+                let result = escape(0.5 + step as f64*0.001);
+                assert!(result.escaped);
+                assert!(result.smooth <= last.smooth && last.smooth - result.smooth < 0.05, "{} jumps at {}", name, step);
+                last = result;
+            }
+        }
+    }
+
+    #[test]
+    fn conjugate_symmetry() {
+        for &(x, y) in &[(-0.2, 0.7), (-1.3, 0.05), (0.3, 0.5), (-0.75, 0.1)] {
+            assert_eq!(Mandelbrot.escape(x, y, 500, 0.0).smooth, Mandelbrot.escape(x, -y, 500, 0.0).smooth);
+            assert_eq!(Tricorn.escape(x, y, 500, 0.0).smooth, Tricorn.escape(x, -y, 500, 0.0).smooth);
+        }
+    }
+
+    #[test]
+    fn double_double_agrees_with_f64() {
+        for &(x, y) in &[(0.5, 0.0), (-0.75, 0.1), (-0.2, 0.7), (0.3, 0.5)] {
+            let (dx, dy) = (DoubleDouble::from(x), DoubleDouble::from(y));
+            assert_eq!(Mandelbrot.escape_precise(&dx, &dy, 500, 0.0).iter, Mandelbrot.escape(x, y, 500, 0.0).iter);
+            assert_eq!(Julia::new(-0.8, 0.156).escape_precise(&dx, &dy, 500, 0.0).iter, Julia::new(-0.8, 0.156).escape(x, y, 500, 0.0).iter);
+        }
+    }
+
+    /// Iterates `z = z^power + c` the slow way, through `exp` and `log`.
+    fn naive_multibrot(power: f64, c: Complex<f64>, max_iter: u64, bailout: f64) -> u64 {
+        let mut z = Complex::new(0.0, 0.0);
+        for iter in 0..max_iter {
+            if z.norm_sqr() >= bailout { return iter }
+            z = z.powc(Complex::new(power, 0.0)) + c;
+        }
+        max_iter
+    }
+
+    #[test]
+    fn multibrot_powers_match_exp_log() {
+        for &power in &[-2.0, -3.0, -1.5, 0.5, 2.5, 3.7] {
+            let multibrot = Multibrot::new(power);
+            for i in 0..40 {
+                for j in 0..40 {
+                    let c = Complex::new(-2.0 + i as f64*0.1 + 0.013, -2.0 + j as f64*0.1 + 0.007);
+                    assert_eq!(multibrot.iterate(c.re, c.im, 200, 0.0), naive_multibrot(power, c, 200, 4.0), "power {} at {:?}", power, c);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn negative_multibrot_powers() {
+        let multibrot = Multibrot::new(-2.0);
+        // z stays 0 rather than dividing by it
+        let result = multibrot.escape(0.0, 0.0, 100, 0.0);
+        assert!(!result.escaped);
+        assert_eq!(result.z, (0.0, 0.0));
+        // Orbits settle near c, so only huge c escape, after one step and
+        // without a smooth value since z^-2 shrinks as z grows
+        assert_eq!(multibrot.escape(300.0, 0.0, 100, 0.0).smooth, 1.0);
+        assert_eq!(multibrot.iterate(3.0, 0.0, 100, 0.0), 1);
+        let result = multibrot.escape(3.0, 0.0, 100, 0.0);
+        assert!(!result.escaped);
+        assert!((result.z.0 - 3.1038).abs() < 1e-4 && result.z.1 == 0.0, "{:?}", result.z);
+        assert_eq!(result.derivative, None);
+    }
+
+    #[test]
+    fn fractional_multibrot_powers() {
+        // Just off 2 the polar form has to agree with plain squaring
+        let nearly = Multibrot::new(2.0 + 1e-12);
+        for &(x, y) in &[(0.5, 0.0), (-0.75, 0.1), (0.3, 0.5), (-0.2, 0.7), (-1.0, 0.0), (0.0, 0.0)] {
+            assert_eq!(nearly.iterate(x, y, 500, 0.0), Mandelbrot.iterate(x, y, 500, 0.0));
+            let (a, b) = (nearly.escape(x, y, 500, 0.0), Mandelbrot.escape(x, y, 500, 0.0));
+            assert!((a.smooth - b.smooth).abs() < 1e-6, "{} against {}", a.smooth, b.smooth);
+        }
+
+        // Smooth values follow the power and don't jump between bands
+        let multibrot = Multibrot::new(2.5);
+        let mut last = multibrot.escape(0.6, 0.0, 1000, 0.0);
+        for step in 1..2000 {
+            let result = multibrot.escape(0.6 + step as f64*0.001, 0.0, 1000, 0.0);
+            assert!(result.escaped);
+            assert!(result.smooth <= last.smooth && last.smooth - result.smooth < 0.05, "jumps at {}", step);
+            last = result;
+        }
+        // Powers under 1 aren't normalized
+        assert_eq!(Multibrot::new(0.5).escape(3.0, 0.0, 100, 0.0).smooth, Multibrot::new(0.5).escape(3.0, 0.0, 100, 0.0).iter as f64);
+    }
+}
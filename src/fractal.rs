@@ -0,0 +1,298 @@
+use super::{RenderingContext, Precision, Strategy, Real, Complex};
+
+/// A formula that can be rendered by `render_image`, `render_animation` and `render_vfr`.
+///
+/// `t` is the animation parameter, normalized to `[0, 1)` over the length of
+/// the animation. Still images are rendered with `t = 0.0`.
+pub trait Fractal: Send + Sync {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64;
+
+    /// Everything the formula knows about how the point escaped. This is what
+    /// the render functions color from.
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        EscapeResult::from_count(self.iterate(x0, y0, max_iter, t), max_iter)
+    }
+
+    /// `escape` at a point given as any `Real`, used when the context asks
+    /// for more precision than `f64`. Formulas that can only be iterated in
+    /// `f64` keep the default, which rounds the point.
+    fn escape_precise<T: Real>(&self, x0: &T, y0: &T, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape(x0.to_f64(), y0.to_f64(), max_iter, t)
+    }
+
+    /// `escape`, calling `visit` with every `z` of the orbit along the way.
+    /// This is what orbit traps are measured with. Formulas that can't report
+    /// their orbit keep the default, which never calls `visit`.
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, _visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        self.escape(x0, y0, max_iter, t)
+    }
+
+    /// Fractional escape value used for coloring. Anything at or above
+    /// `max_iter` is treated as inside the set.
+    fn smooth(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> f64 {
+        self.escape(x0, y0, max_iter, t).smooth
+    }
+
+    fn name(&self) -> &str { "fractal" }
+
+    fn default_context(&self) -> RenderingContext {
+        RenderingContext {
+            x: 0.0.into(), y: 0.0.into(),
+            scale: 4.0, max_iter: 256,
+            x_px: 512, y_px: 512,
+            precision: Precision::F64,
+            strategy: Strategy::EveryPixel,
+        }
+    }
+}
+
+/// The outcome of iterating a single point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EscapeResult {
+    pub iter: u64,
+    /// Fractional iteration count, equal to `iter` for formulas that can't
+    /// compute anything better
+    pub smooth: f64,
+    /// False if the point hit `max_iter` or was otherwise proven to be inside
+    pub escaped: bool,
+    /// The last `z` of the orbit
+    pub z: (f64, f64),
+    /// `dz/dc` at the last iteration, if the formula tracks it
+    pub derivative: Option<(f64, f64)>,
+    /// Minimum distance from the orbit to a trap, if the formula tracks it
+    pub trap: Option<f64>,
+    /// Index of the root a root finding formula converged to
+    pub root: Option<usize>,
+    /// Period of the attracting cycle a point inside the set settled into,
+    /// if the formula looks for one
+    pub period: Option<u64>,
+    /// Estimated distance from a point inside the set to its edge, if the
+    /// formula can tell
+    pub interior_distance: Option<f64>,
+}
+
+impl EscapeResult {
+    /// A result carrying only an iteration count.
+    pub fn from_count(iter: u64, max_iter: u64) -> EscapeResult {
+        EscapeResult {
+            iter: iter.min(max_iter), smooth: iter.min(max_iter) as f64,
+            escaped: iter < max_iter,
+            z: (0.0, 0.0),
+            derivative: None, trap: None, root: None,
+            period: None, interior_distance: None,
+        }
+    }
+
+    pub fn escaped(iter: u64, smooth: f64, z: (f64, f64)) -> EscapeResult {
+        EscapeResult {
+            iter, smooth,
+            escaped: true,
+            z,
+            derivative: None, trap: None, root: None,
+            period: None, interior_distance: None,
+        }
+    }
+
+    pub fn interior(max_iter: u64, z: (f64, f64)) -> EscapeResult {
+        EscapeResult {
+            iter: max_iter, smooth: max_iter as f64,
+            escaped: false,
+            z,
+            derivative: None, trap: None, root: None,
+            period: None, interior_distance: None,
+        }
+    }
+
+    /// Estimated distance from the point to the set, from the final `z` and
+    /// `dz/dc`. `None` if the point didn't escape or the derivative wasn't tracked.
+    pub fn distance(&self) -> Option<f64> {
+        if !self.escaped { return None }
+        let (dx, dy) = self.derivative?;
+        let r = (self.z.0*self.z.0 + self.z.1*self.z.1).sqrt();
+        Some(r * r.ln() / (dx*dx + dy*dy).sqrt())
+    }
+}
+
+/// Squared escape radius used when computing smooth values. A larger radius
+/// makes the normalized iteration count closer to continuous.
+pub const SMOOTH_BAILOUT: f64 = 65536.0;
+
+/// Normalized iteration count for an orbit that escaped after `iter` steps
+/// with `norm = |z|^2`, for a formula of degree `power`.
+pub fn normalized(iter: u64, norm: f64, bailout: f64, power: f64, max_iter: u64) -> f64 {
+    let nu = iter as f64 + 1.0 - (norm.ln()/bailout.ln()).ln()/power.ln();
+    nu.max(0.0).min(max_iter as f64 - 1e-9)
+}
+
+/// Smooth iteration count for an orbit that converged on step `iter`, from
+/// how far it moved on that step and the one before.
+pub fn converged(iter: u64, distance: f64, last: f64, tolerance: f64, max_iter: u64) -> f64 {
+    // How far between the last two steps the tolerance was crossed on a log
+    // scale, convergence is too fast for anything linear
+    let fraction = if last.is_finite() { (tolerance.ln() - last.ln()) / (distance.ln() - last.ln()) } else { 1.0 };
+    (iter as f64 - 1.0 + fraction.clamp(0.0, 1.0)).min(max_iter as f64 - 1e-9)
+}
+
+/// When the orbit of a `Stateful` formula stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bailout {
+    /// The orbit escapes once `|z|^2` reaches this
+    pub divergence: Option<f64>,
+    /// The orbit converges once `Stateful::distance` drops below this
+    pub convergence: Option<f64>,
+}
+
+/// A formula whose orbit carries more than `z` from step to step, such as
+/// earlier values of `z`, and which can stop by converging as well as by
+/// escaping.
+///
+/// `orbit` runs the whole thing, so the `Fractal` implementation only has to
+/// forward `escape` and `escape_visit` to it. Both kinds of stopping count as
+/// escaping as far as coloring goes. Orbits that do neither within `max_iter`
+/// are inside the set.
+pub trait Stateful {
+    /// Whatever the orbit needs besides `z`.
+    type State;
+
+    /// The state and `z` the orbit of `c` starts from.
+    fn start(&self, c: Complex<f64>, t: f64) -> (Self::State, Complex<f64>);
+
+    /// Takes one step from `z`, returning the next `z`.
+    fn step(&self, state: &mut Self::State, z: Complex<f64>, c: Complex<f64>) -> Complex<f64>;
+
+    fn bailout(&self) -> Bailout;
+
+    /// Degree of the formula for large `z`, used to smooth the iteration
+    /// count of escaping orbits. Anything at or below 1 isn't smoothed.
+    fn power(&self) -> f64 { 2.0 }
+
+    /// How far the orbit is from having converged after stepping from `z`
+    /// to `next`. Defaults to the size of the step.
+    fn distance(&self, z: Complex<f64>, next: Complex<f64>) -> f64 {
+        (next - z).norm()
+    }
+
+    /// Index of the attractor a converged orbit ended up at, for `Coloring::Root`.
+    fn root(&self, _z: Complex<f64>) -> Option<usize> { None }
+
+    /// Follows the orbit of the pixel until it escapes, converges or runs
+    /// out of iterations, calling `visit` with every `z`.
+    fn orbit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let c = Complex::new(x0, y0);
+        let bailout = self.bailout();
+        let (mut state, mut z) = self.start(c, t);
+        let mut last = f64::INFINITY;
+
+        for iter in 1..=max_iter {
+            let next = self.step(&mut state, z, c);
+            if !next.re.is_finite() || !next.im.is_finite() { break; }
+            visit(next.re, next.im);
+
+            if let Some(radius) = bailout.divergence {
+                let norm = next.norm_sqr();
+                if norm >= radius {
+                    let power = self.power();
+                    let smooth = if power > 1.0 { normalized(iter, norm, radius, power, max_iter) } else { iter as f64 };
+                    return EscapeResult::escaped(iter, smooth, (next.re, next.im));
+                }
+            }
+
+            if let Some(tolerance) = bailout.convergence {
+                let distance = self.distance(z, next);
+                if distance < tolerance {
+                    let mut result = EscapeResult::escaped(iter, converged(iter, distance, last, tolerance, max_iter), (next.re, next.im));
+                    result.root = self.root(next);
+                    return result;
+                }
+                last = distance;
+            }
+
+            z = next;
+        }
+
+        EscapeResult::interior(max_iter, (z.re, z.im))
+    }
+}
+
+/// Anything a formula closure may return.
+pub trait IntoEscapeResult {
+    fn into_escape_result(self, max_iter: u64) -> EscapeResult;
+}
+
+impl IntoEscapeResult for u64 {
+    fn into_escape_result(self, max_iter: u64) -> EscapeResult { EscapeResult::from_count(self, max_iter) }
+}
+
+impl IntoEscapeResult for EscapeResult {
+    fn into_escape_result(self, _max_iter: u64) -> EscapeResult { self }
+}
+
+/// Anything the render functions accept in place of a `Fractal`.
+///
+/// `Marker` only exists so that closures of different shapes can be accepted
+/// without the implementations overlapping. `frames` is the number of frames
+/// `t` is split into, and is 1 for still images.
+pub trait IntoFractal<Marker> {
+    type Fractal: Fractal + 'static;
+    fn into_fractal(self, frames: u32) -> Self::Fractal;
+}
+
+impl<F> IntoFractal<F> for F where F: Fractal + 'static {
+    type Fractal = F;
+    fn into_fractal(self, _frames: u32) -> F { self }
+}
+
+pub struct StillFn<F>(F);
+
+impl<F, R> Fractal for StillFn<F> where F: Fn(f64, f64, u64) -> R + Send + Sync, R: IntoEscapeResult {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.escape(x0, y0, max_iter, t).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, _t: f64) -> EscapeResult {
+        (self.0)(x0, y0, max_iter).into_escape_result(max_iter)
+    }
+}
+
+impl<F, R> IntoFractal<fn(f64, f64, u64) -> R> for F where F: Fn(f64, f64, u64) -> R + Send + Sync + 'static, R: IntoEscapeResult {
+    type Fractal = StillFn<F>;
+    fn into_fractal(self, _frames: u32) -> StillFn<F> { StillFn(self) }
+}
+
+pub struct FrameFn<F> {
+    frac: F,
+    frames: u32,
+}
+
+impl<F, R> Fractal for FrameFn<F> where F: Fn(f64, f64, u64, u32) -> R + Send + Sync, R: IntoEscapeResult {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.escape(x0, y0, max_iter, t).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        let frame = (t * self.frames as f64).round() as u32 % self.frames.max(1);
+        (self.frac)(x0, y0, max_iter, frame).into_escape_result(max_iter)
+    }
+}
+
+impl<F, R> IntoFractal<fn(f64, f64, u64, u32) -> R> for F where F: Fn(f64, f64, u64, u32) -> R + Send + Sync + 'static, R: IntoEscapeResult {
+    type Fractal = FrameFn<F>;
+    fn into_fractal(self, frames: u32) -> FrameFn<F> { FrameFn { frac: self, frames } }
+}
+
+pub struct TimeFn<F>(F);
+
+impl<F, R> Fractal for TimeFn<F> where F: Fn(f64, f64, u64, f64) -> R + Send + Sync, R: IntoEscapeResult {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.escape(x0, y0, max_iter, t).iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        (self.0)(x0, y0, max_iter, t).into_escape_result(max_iter)
+    }
+}
+
+impl<F, R> IntoFractal<fn(f64, f64, u64, f64) -> R> for F where F: Fn(f64, f64, u64, f64) -> R + Send + Sync + 'static, R: IntoEscapeResult {
+    type Fractal = TimeFn<F>;
+    fn into_fractal(self, _frames: u32) -> TimeFn<F> { TimeFn(self) }
+}
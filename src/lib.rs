@@ -3,8 +3,53 @@ extern crate spmc;
 extern crate num_cpus;
 extern crate pbr;
 
+mod error;
+pub use self::error::Error;
+mod progress;
+pub use self::progress::{Progress, TerminalProgress, Status, Phase, Cancel, Monitor, Stage};
+
 mod context;
-pub use self::context::{RenderingContext, ColorScheme};
+pub use self::context::{RenderingContext, ColorScheme, ParseContextError, Precision, Strategy};
+
+mod fractal;
+pub use self::fractal::{Fractal, IntoFractal, EscapeResult, IntoEscapeResult, Stateful, Bailout};
+
+pub mod formulas;
 
 mod util;
-pub use self::util::{render_image, render_animation};
+pub use self::util::{render_image, render_image_rgb, render_samples, render_animation, render_animation_rgb, render_animation_with, colorize, Coloring, Interior, Sample};
+
+mod bigfloat;
+pub use self::bigfloat::{BigFloat, ParseBigFloatError};
+
+mod real;
+pub use self::real::{Real, Complex};
+
+mod doubledouble;
+pub use self::doubledouble::DoubleDouble;
+
+mod rng;
+
+mod density;
+pub use self::density::{render_density, render_density_rgb, Density, ToneMapping};
+
+mod trap;
+pub use self::trap::{Trap, Trapped, TrapSource};
+
+mod expr;
+pub use self::expr::{Formula, ParseFormulaError};
+
+mod lyapunov;
+pub use self::lyapunov::{render_lyapunov, render_lyapunov_rgb, Lyapunov};
+
+mod flame;
+pub use self::flame::{render_flame, render_flame_rgb, Flame, Transform, Variation};
+
+mod raymarch;
+pub use self::raymarch::{render_ray_marched, render_ray_marched_rgb, render_ray_marched_animation, render_ray_marched_animation_with, Vector, Camera, CameraPath, DistanceEstimator, Mandelbulb, Mandelbox, RayMarcher};
+
+mod quaternion;
+pub use self::quaternion::{Quaternion, Axis, SlicePath, QuaternionJulia};
+
+mod deep;
+pub use self::deep::{render_deep_zoom, render_deep_zoom_rgb};
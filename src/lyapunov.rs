@@ -0,0 +1,166 @@
+use std::path::Path;
+use super::{RenderingContext, ColorScheme, Precision, Strategy, Error, Monitor};
+use super::util::render_tiles;
+use image;
+use image::ImageBuffer;
+
+/// Settings for `render_lyapunov`.
+#[derive(Clone, Debug)]
+pub struct Lyapunov {
+    /// Which of `a` (`false`) and `b` (`true`) drives each step, repeated
+    pub sequence: Vec<bool>,
+    /// Steps taken before the exponent starts being measured
+    pub warmup: u64,
+    /// Steps the exponent is averaged over
+    pub iterations: u64,
+}
+
+impl Lyapunov {
+    /// Reads a sequence like `"AABAB"`. `None` if it is empty or has
+    /// anything but `A` and `B` in it, in either case, or if `iterations` is
+    /// zero, which leaves nothing to average over.
+    pub fn new(sequence: &str, warmup: u64, iterations: u64) -> Option<Lyapunov> {
+        let sequence = sequence.chars().map(|c| match c {
+            'A' | 'a' => Some(false),
+            'B' | 'b' => Some(true),
+            _ => None,
+        }).collect::<Option<Vec<bool>>>()?;
+        if sequence.is_empty() || iterations == 0 { return None }
+        Some(Lyapunov { sequence, warmup, iterations })
+    }
+
+    /// Both rates from 2 to 4, where all the interesting parts are.
+    pub fn default_context(&self) -> RenderingContext {
+        RenderingContext {
+            x: 3.0.into(), y: 3.0.into(),
+            scale: 2.0, max_iter: self.iterations,
+            x_px: 512, y_px: 512,
+            precision: Precision::F64,
+            strategy: Strategy::EveryPixel,
+        }
+    }
+
+    /// The Lyapunov exponent of the logistic map `x = r*x*(1 - x)` with `r`
+    /// switching between `a` and `b` as the sequence says. Rates outside of
+    /// 0 to 4 throw `x` off to infinity, which counts as infinitely chaotic.
+    pub fn exponent(&self, a: f64, b: f64) -> f64 {
+        let mut x = 0.5;
+        let mut steps = self.sequence.iter().cycle();
+        for _ in 0..self.warmup {
+            let r = if *steps.next().unwrap() { b } else { a };
+            x = r*x*(1.0 - x);
+        }
+        if !x.is_finite() { return f64::INFINITY }
+
+        // Multiplying the derivatives together and only taking the log when
+        // the product gets out of hand is much faster than a log every step
+        let mut sum = 0.0;
+        let mut product = 1.0;
+        for _ in 0..self.iterations {
+            let r = if *steps.next().unwrap() { b } else { a };
+            x = r*x*(1.0 - x);
+            if !x.is_finite() { return f64::INFINITY }
+            product *= (r*(1.0 - 2.0*x)).abs();
+            if !(1e-100..=1e100).contains(&product) {
+                if product == 0.0 { return f64::NEG_INFINITY }
+                sum += product.ln();
+                product = 1.0;
+            }
+        }
+        (sum + product.ln()) / self.iterations as f64
+    }
+}
+
+/// Renders a Lyapunov fractal with `a` along x and `b` along y of `ctx`.
+///
+/// Points with a negative exponent, where the map settles down, are colored
+/// from `stable` and points with a positive exponent, where it is chaotic,
+/// from `chaotic`. Both run from the start of the scheme at an exponent of 0
+/// towards the end as the exponent grows. `ctx.max_iter` is ignored in
+/// favor of `lyapunov.iterations`.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_lyapunov, ColorScheme, Lyapunov, Monitor};
+/// # fn main() {
+/// # let (stable, chaotic) = (ColorScheme::new(), ColorScheme::new());
+/// let zircon = Lyapunov::new("BBBBBBAAAAAA", 200, 1000).unwrap();
+/// render_lyapunov(zircon.default_context(), &stable, &chaotic, &Path::new("zircon.png"), &zircon, &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+pub fn render_lyapunov(ctx: RenderingContext, stable: &ColorScheme, chaotic: &ColorScheme, path: &Path, lyapunov: &Lyapunov, monitor: &Monitor) -> Result<(), Error> {
+    image::ImageRgb8(render_lyapunov_rgb(ctx, stable, chaotic, lyapunov, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_lyapunov` without the file, handing back the picture instead.
+pub fn render_lyapunov_rgb(ctx: RenderingContext, stable: &ColorScheme, chaotic: &ColorScheme, lyapunov: &Lyapunov, monitor: &Monitor) -> Result<image::RgbImage, Error> {
+    stable.validate()?;
+    chaotic.validate()?;
+    let exponents = {
+        let lyapunov = lyapunov.clone();
+        render_tiles::<f64, _, _>(&ctx, monitor, move |&a, &b, _, _| lyapunov.exponent(a, b))?
+    };
+
+    let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let exponent = exponents[x as usize + y as usize*ctx.x_px as usize];
+        *pixel = if exponent < 0.0 {
+            stable.get_color(1.0 - exponent.exp())
+        } else {
+            chaotic.get_color(1.0 - (-exponent).exp())
+        };
+    }
+
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lyapunov;
+
+    #[test]
+    fn sequences_are_checked() {
+        assert!(Lyapunov::new("", 0, 100).is_none());
+        assert!(Lyapunov::new("ABX", 0, 100).is_none());
+        assert!(Lyapunov::new("ab ", 0, 100).is_none());
+        assert!(Lyapunov::new("AB", 0, 0).is_none());
+        // Either case reads the same
+        let mixed = Lyapunov::new("aBbA", 0, 100).unwrap();
+        assert_eq!(mixed.sequence, Lyapunov::new("ABBA", 0, 100).unwrap().sequence);
+        assert_eq!(mixed.sequence, vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn stable_rates_have_negative_exponents() {
+        // At r = 2.5 the map settles on x = 0.6, where its slope is -0.5
+        let lyapunov = Lyapunov::new("AB", 100, 1000).unwrap();
+        let exponent = lyapunov.exponent(2.5, 2.5);
+        assert!((exponent - 0.5f64.ln()).abs() < 1e-12, "{}", exponent);
+    }
+
+    #[test]
+    fn chaotic_rates_have_positive_exponents() {
+        // Starting from x = 0.5, r = 4 lands exactly on the fixed point at 0
+        // in two steps, where the slope is 4
+        let lyapunov = Lyapunov::new("AB", 0, 1000).unwrap();
+        assert!((lyapunov.exponent(4.0, 4.0) - 4f64.ln()).abs() < 1e-12);
+        // Starting with any other rate the orbit wanders around the whole
+        // interval instead, where the exponent comes out near ln 2
+        let exponent = lyapunov.exponent(3.99, 4.0);
+        assert!(exponent > 0.0 && (exponent - 2f64.ln()).abs() < 0.05, "{}", exponent);
+        // Rates past 4 throw x out of the interval and off to infinity
+        assert_eq!(lyapunov.exponent(4.5, 4.5), f64::INFINITY);
+    }
+
+    #[test]
+    fn warmup_skips_the_transient() {
+        // Without a warmup the first steps, still on their way to x = 0.6,
+        // count towards the average
+        let cold = Lyapunov::new("A", 0, 10).unwrap().exponent(2.5, 2.5);
+        let warm = Lyapunov::new("A", 100, 10).unwrap().exponent(2.5, 2.5);
+        assert!((warm - 0.5f64.ln()).abs() < 1e-12, "{}", warm);
+        assert!((cold - 0.5f64.ln()).abs() > 1e-3, "{}", cold);
+    }
+}
@@ -1,26 +1,30 @@
 extern crate fractal;
 extern crate image;
-extern crate pbr;
 
+use std::env;
+use std::process;
 use std::path::Path;
-use fractal::{ColorScheme, RenderingContext};
+use fractal::{ColorScheme, RenderingContext, Fractal, IntoFractal, Precision, Strategy, Real, DoubleDouble, BigFloat, Interior};
+use fractal::{render_image, Formula, ParseFormulaError, Error, Monitor, Phase};
+use fractal::formulas::SineJulia;
 use std::f64::consts::PI;
 use std::rc::Rc;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use pbr::ProgressBar;
 use image::ImageBuffer;
 
 struct Frame {
     t: f64,
-    image: Box<Vec<u64>>,
+    image: Vec<f64>,
+    /// `Interior::value` of the pixels inside the set
+    inside: Vec<Option<f64>>,
 }
 
 impl Frame {
-    fn difference(&self, other: &Rc<Frame>) -> u64 {
-        let mut total = 0;
+    fn difference(&self, other: &Rc<Frame>) -> f64 {
+        let mut total = 0.0;
         for i in 0..self.image.len() {
-            total += (self.image[i] as i64 - other.image[i] as i64).abs() as u64;
+            total += (self.image[i] - other.image[i]).abs();
         }
         total
     }
@@ -46,11 +50,10 @@ impl PartialEq for Frame {
     fn eq(&self, other: &Frame) -> bool { self.t == other.t }
 }
 
-#[derive(Eq)]
 struct Interval {
     a: Rc<Frame>,
     b: Rc<Frame>,
-    difference: u64,
+    difference: f64,
 }
 
 impl Interval {
@@ -70,8 +73,11 @@ impl Interval {
 }
 
 impl Ord for Interval {
+    // Same caveat as Frame, differences are sums of finite values
     fn cmp(&self, other: &Interval) -> Ordering { 
-        self.difference.cmp(&other.difference)
+        if self.difference == other.difference { Ordering::Equal }
+        else if self.difference > other.difference { Ordering::Greater }
+        else { Ordering::Less }
     }
 }
 
@@ -79,33 +85,44 @@ impl PartialOrd for Interval {
     fn partial_cmp(&self, other: &Interval) -> Option<Ordering> { Some(self.cmp(other)) }
 }
 
+impl Eq for Interval { }
+
 impl PartialEq for Interval {
     fn eq(&self, other: &Interval) -> bool { self.difference == other.difference }
 }
 
-fn render_frame<F>(ctx: &RenderingContext, frac: &F, t: f64) -> Frame where F: Fn(f64, f64, u64, f64) -> u64 + Send + Sync + 'static {
-    let mut image = Vec::with_capacity(ctx.x_px as usize*ctx.y_px as usize);
-    for _ in 0..ctx.x_px*ctx.y_px { image.push(0); }
+fn render_frame<F>(ctx: &RenderingContext, frac: &F, interior: Interior, t: f64) -> Frame where F: Fractal {
+    match ctx.precision {
+        Precision::F64 => render_frame_as::<f64, F>(ctx, frac, interior, t),
+        Precision::DoubleDouble => render_frame_as::<DoubleDouble, F>(ctx, frac, interior, t),
+        Precision::BigFloat => render_frame_as::<BigFloat, F>(ctx, frac, interior, t),
+    }
+}
+
+fn render_frame_as<T, F>(ctx: &RenderingContext, frac: &F, interior: Interior, t: f64) -> Frame where T: Real, F: Fractal {
+    let mut image = vec![0.0; ctx.x_px as usize*ctx.y_px as usize];
+    let mut inside = vec![None; ctx.x_px as usize*ctx.y_px as usize];
 
-    for (x0, y0, x_px, y_px) in ctx.enumerate_points() {
-        let iter = frac(x0, y0, ctx.max_iter, t);
-        image[x_px as usize + y_px as usize * ctx.x_px as usize] = iter;
+    for (x0, y0, x_px, y_px) in ctx.enumerate_points_as::<T>() {
+        let result = frac.escape_precise(&x0, &y0, ctx.max_iter, t);
+        let i = x_px as usize + y_px as usize * ctx.x_px as usize;
+        image[i] = result.smooth;
+        if !result.escaped { inside[i] = Some(interior.value(&result, ctx)) }
     }
 
-    let image = Box::new(image);
-    Frame{image, t}
+    Frame{image, inside, t}
 }
 
-fn render_vfr<F>(ctx: RenderingContext, cs: ColorScheme, path: &'static Path, frame_count: u32, frac: F) where F: Fn(f64, f64, u64, f64) -> u64 + Send + Sync + 'static {
-    let mut pb = ProgressBar::new(frame_count as u64);
-    pb.format("[=> ]");
-    pb.message("Rendering frames ");
-    pb.add(0);
+fn render_vfr<M, F>(ctx: RenderingContext, cs: ColorScheme, path: &'static Path, frame_count: u32, frac: F, monitor: &Monitor) -> Result<(), Error> where F: IntoFractal<M> {
+    ctx.validate()?;
+    cs.validate()?;
+    let frac = frac.into_fractal(frame_count);
+    let stage = monitor.phase(Phase::Rendering, frame_count as u64);
 
     let mut frames : Vec<Rc<Frame>> = Vec::with_capacity(4); 
     for i in 0..4 {
-        frames.push(Rc::new(render_frame(&ctx, &frac, i as f64/4.0)));
-        pb.inc();
+        frames.push(Rc::new(render_frame(&ctx, &frac, cs.interior(), i as f64/4.0)));
+        stage.step()?;
     }
 
     let mut pq = BinaryHeap::new();
@@ -116,32 +133,45 @@ fn render_vfr<F>(ctx: RenderingContext, cs: ColorScheme, path: &'static Path, fr
 
     while frames.len() < frame_count as usize {
         let interval = pq.pop().unwrap();
-        let f = Rc::new(render_frame(&ctx, &frac, interval.midpoint()));
+        let f = Rc::new(render_frame(&ctx, &frac, cs.interior(), interval.midpoint()));
         frames.push(f.clone());
         let (a, b) = interval.subdivide(f);
         pq.push(a);
         pq.push(b);
-        pb.inc();
+        stage.step()?;
     }
 
-    pb.finish();
-
     frames.sort();
 
-    for i in 0..frames.len() {
-        eprintln!("{}, {}", i, frames[i as usize].t);
+    let stage = monitor.phase(Phase::Writing, frames.len() as u64);
+    for (i, frame) in frames.iter().enumerate() {
         let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
-        let image = &frames[i as usize].image;
+        let image = &frame.image;
         for (x, y, pixel) in img.enumerate_pixels_mut() {
-            let iter = image[x as usize + y as usize*ctx.x_px as usize];
-            if iter == ctx.max_iter {
-                *pixel = image::Rgb([0, 0, 0]);
-            } else {
-                *pixel = cs.get_color(iter as f64/ctx.max_iter as f64);
+            let i = x as usize + y as usize*ctx.x_px as usize;
+            match frame.inside[i] {
+                Some(value) => *pixel = cs.interior_color(value),
+                None => *pixel = cs.get_color(image[i]/ctx.max_iter as f64),
             }
         }
-        image::ImageRgb8(img).save(path.join(Path::new(&format!("frame{}.png", i)))).unwrap();
+        image::ImageRgb8(img).save(path.join(Path::new(&format!("frame{}.png", i))))?;
+        stage.step()?;
     }
+    Ok(())
+}
+
+/// Reads `fractal <formula> [bailout] [initial z]`, pointing out where
+/// whichever part didn't parse went wrong.
+fn parse_formula(args: &[String]) -> Result<Formula, String> {
+    let report = |source: &str, err: ParseFormulaError| format!("{0}\n{1:>2$}\n{3}", source, "^", err.column, err);
+    let mut formula = Formula::new(&args[0]).map_err(|err| report(&args[0], err))?;
+    if let Some(bailout) = args.get(1) {
+        formula = formula.bailout(bailout).map_err(|err| report(bailout, err))?;
+    }
+    if let Some(initial) = args.get(2) {
+        formula = formula.initial(initial).map_err(|err| report(initial, err))?;
+    }
+    Ok(formula)
 }
 
 fn main() {
@@ -186,26 +216,32 @@ fn main() {
     //cs.add_hex(0xff00ff, 1.0/2.0);
     //cs.add_hex(0xffffff, 2.0/2.0);
 
+    // A formula on the command line is rendered to formula.png instead
+    let args : Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        let formula = parse_formula(&args).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        if let Err(err) = render_image(formula.default_context(), &cs, Path::new("formula.png"), formula, &Monitor::terminal()) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let ctx = RenderingContext { 
-        x: 0.0, y: 0.0, 
+        x: 0.0.into(), y: 0.0.into(), 
         scale: 12.0, max_iter: 50, 
-        x_px: 256, y_px: 256,};
-
-    render_vfr(ctx, cs, Path::new("frames"), 3000, |x0, y0, max_iter, t| {
-            let mut x = x0;
-            let mut y = y0;
-            let cx = (PI*t).sin();
-            let cy = (PI*t).cos();
-            let mut iter = 0;
-    
-            while y.abs() < 50.0 && iter < max_iter {
-                let xtemp = x.sin()*y.cosh();
-                let ytemp = x.cos()*y.sinh();
-                x = cx*xtemp - cy*ytemp;
-                y = cx*ytemp + cy*xtemp;
-                iter += 1;
-            }
-    
-            iter
-        });
+        x_px: 256, y_px: 256,
+        precision: Precision::F64,
+        strategy: Strategy::EveryPixel,};
+
+    let result = render_vfr(ctx, cs, Path::new("frames"), 3000, |x0: f64, y0: f64, max_iter: u64, t: f64| {
+            SineJulia::new((PI*t).sin(), (PI*t).cos()).escape(x0, y0, max_iter, t)
+        }, &Monitor::terminal());
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
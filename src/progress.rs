@@ -0,0 +1,247 @@
+use std::fmt;
+use std::io::Stdout;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use super::Error;
+use pbr::ProgressBar;
+
+/// What a render is busy with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Setting aside memory for the frames of an animation
+    Allocating,
+    /// Working out every tile, frame or pixel
+    Rendering,
+    /// Throwing random points, for the renders built from orbits
+    Sampling,
+    /// Turning finished frames into colors
+    Coloring,
+    /// Saving pictures
+    Writing,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Allocating => write!(f, "Allocating"),
+            Phase::Rendering => write!(f, "Rendering"),
+            Phase::Sampling => write!(f, "Sampling"),
+            Phase::Coloring => write!(f, "Coloring"),
+            Phase::Writing => write!(f, "Writing"),
+        }
+    }
+}
+
+/// How far along a phase of a render is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub phase: Phase,
+    /// Tiles, frames, batches or pixels finished so far
+    pub done: u64,
+    /// How many there are in this phase
+    pub total: u64,
+    /// Time since the phase started
+    pub elapsed: Duration,
+}
+
+impl Status {
+    /// Time left in the phase if it keeps going at the pace it has so far,
+    /// `None` until the first step is done.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 { return None }
+        Some(self.elapsed.mul_f64(self.total.saturating_sub(self.done) as f64 / self.done as f64))
+    }
+}
+
+/// Gets told about every step a render takes.
+///
+/// `update` is called once with `done` at zero as each phase starts and again
+/// after every step, from whichever worker thread took it, so steps can
+/// arrive slightly out of order.
+///
+/// ```
+/// # extern crate fractal;
+/// # use std::sync::{Arc, Mutex};
+/// # use fractal::{render_image_rgb, ColorScheme, Fractal, Monitor, Progress, Status};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// struct Log(Arc<Mutex<Vec<Status>>>);
+///
+/// impl Progress for Log {
+///     fn update(&self, status: &Status) {
+///         self.0.lock().unwrap().push(*status);
+///     }
+/// }
+///
+/// # let mut cs = ColorScheme::new();
+/// # cs.add_hex(0xffffff, 0.0);
+/// let mut ctx = Mandelbrot.default_context();
+/// ctx.x_px = 64;
+/// ctx.y_px = 64;
+/// let statuses = Arc::new(Mutex::new(Vec::new()));
+/// let monitor = Monitor::new().with_progress(Log(statuses.clone()));
+/// render_image_rgb(ctx, &cs, Mandelbrot, &monitor).unwrap();
+/// // One update as rendering starts and one for every step after
+/// let statuses = statuses.lock().unwrap();
+/// let total = statuses[0].total;
+/// assert_eq!(statuses.len() as u64, total + 1);
+/// assert_eq!(statuses.iter().map(|status| status.done).max(), Some(total));
+/// # }
+/// ```
+pub trait Progress: Send + Sync {
+    fn update(&self, status: &Status);
+}
+
+/// A `pbr` progress bar on the terminal, started afresh for every phase.
+#[derive(Default)]
+pub struct TerminalProgress {
+    /// The phase the bar is showing and the furthest it has got
+    bar: Mutex<Option<(Phase, u64, ProgressBar<Stdout>)>>,
+}
+
+impl TerminalProgress {
+    pub fn new() -> TerminalProgress {
+        TerminalProgress::default()
+    }
+}
+
+impl Progress for TerminalProgress {
+    fn update(&self, status: &Status) {
+        let mut bar = match self.bar.lock() {
+            Ok(bar) => bar,
+            Err(_) => return,
+        };
+        let current = match *bar {
+            Some((phase, _, _)) => phase == status.phase && status.done > 0,
+            None => false,
+        };
+        if !current {
+            let mut pb = ProgressBar::new(status.total);
+            pb.format("[=> ]");
+            pb.message(&format!("{} ", status.phase));
+            pb.add(0);
+            *bar = Some((status.phase, 0, pb));
+        }
+        let finished = match *bar {
+            // Steps from different threads can be reported out of order
+            Some((_, ref mut last, ref mut pb)) if status.done > *last => {
+                *last = status.done;
+                pb.set(status.done);
+                status.done >= status.total
+            },
+            _ => status.done >= status.total,
+        };
+        if finished {
+            if let Some((_, _, ref mut pb)) = *bar { pb.finish(); }
+            *bar = None;
+        }
+    }
+}
+
+/// Lets a render be stopped from another thread. Clones share the same flag.
+///
+/// ```
+/// # extern crate fractal;
+/// # use fractal::{render_image_rgb, Cancel, ColorScheme, Fractal, Monitor, Error};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// # let mut cs = ColorScheme::new();
+/// # cs.add_hex(0xffffff, 0.0);
+/// let cancel = Cancel::new();
+/// let monitor = Monitor::new().with_cancel(cancel.clone());
+/// cancel.cancel();
+/// match render_image_rgb(Mandelbrot.default_context(), &cs, Mandelbrot, &monitor) {
+///     Err(Error::Cancelled) => (),
+///     _ => panic!("render wasn't cancelled"),
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    /// Stops every render watching this token after the step it's on.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a render reports its progress to and checks for cancellation.
+///
+/// `Monitor::new()` is silent and runs to the end, `Monitor::terminal()`
+/// shows a progress bar like renders always used to.
+#[derive(Clone, Default)]
+pub struct Monitor {
+    progress: Option<Arc<dyn Progress>>,
+    cancel: Cancel,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    /// A monitor drawing a `TerminalProgress` bar.
+    pub fn terminal() -> Monitor {
+        Monitor::new().with_progress(TerminalProgress::new())
+    }
+
+    pub fn with_progress<P: Progress + 'static>(self, progress: P) -> Monitor {
+        Monitor { progress: Some(Arc::new(progress)), ..self }
+    }
+
+    pub fn with_cancel(self, cancel: Cancel) -> Monitor {
+        Monitor { cancel, ..self }
+    }
+
+    /// `Err(Error::Cancelled)` once the render should stop.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.cancel.is_cancelled() { Err(Error::Cancelled) } else { Ok(()) }
+    }
+
+    /// Starts reporting a phase of `total` steps.
+    pub fn phase(&self, phase: Phase, total: u64) -> Stage {
+        let stage = Stage {
+            monitor: self.clone(),
+            phase,
+            total,
+            done: AtomicU64::new(0),
+            start: Instant::now(),
+        };
+        stage.report(0);
+        stage
+    }
+}
+
+/// One phase of a render in progress, shared between its worker threads.
+pub struct Stage {
+    monitor: Monitor,
+    phase: Phase,
+    total: u64,
+    done: AtomicU64,
+    start: Instant,
+}
+
+impl Stage {
+    /// Counts a finished step, then checks whether to carry on.
+    pub fn step(&self) -> Result<(), Error> {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.report(done);
+        self.monitor.check()
+    }
+
+    fn report(&self, done: u64) {
+        if let Some(ref progress) = self.monitor.progress {
+            progress.update(&Status { phase: self.phase, done, total: self.total, elapsed: self.start.elapsed() });
+        }
+    }
+}
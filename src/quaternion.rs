@@ -0,0 +1,266 @@
+use std::ops::{Add, Mul};
+use super::{Fractal, EscapeResult, RenderingContext, Precision, Strategy, DistanceEstimator, Vector, Camera};
+use super::fractal::{normalized, SMOOTH_BAILOUT};
+
+/// A quaternion with real part `r` and imaginary parts `i`, `j` and `k`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Quaternion {
+    pub r: f64,
+    pub i: f64,
+    pub j: f64,
+    pub k: f64,
+}
+
+/// One of the four components of a `Quaternion`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis { R, I, J, K }
+
+impl Quaternion {
+    pub fn new(r: f64, i: f64, j: f64, k: f64) -> Quaternion {
+        Quaternion { r, i, j, k }
+    }
+
+    pub fn norm_sqr(&self) -> f64 {
+        self.r*self.r + self.i*self.i + self.j*self.j + self.k*self.k
+    }
+
+    /// `self*self`, cheaper than the general product since the imaginary
+    /// parts commute with themselves.
+    pub fn square(&self) -> Quaternion {
+        Quaternion {
+            r: self.r*self.r - self.i*self.i - self.j*self.j - self.k*self.k,
+            i: 2.0*self.r*self.i,
+            j: 2.0*self.r*self.j,
+            k: 2.0*self.r*self.k,
+        }
+    }
+
+    pub fn get(&self, axis: Axis) -> f64 {
+        match axis {
+            Axis::R => self.r,
+            Axis::I => self.i,
+            Axis::J => self.j,
+            Axis::K => self.k,
+        }
+    }
+
+    pub fn set(&mut self, axis: Axis, value: f64) {
+        match axis {
+            Axis::R => self.r = value,
+            Axis::I => self.i = value,
+            Axis::J => self.j = value,
+            Axis::K => self.k = value,
+        }
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Quaternion;
+    fn add(self, other: Quaternion) -> Quaternion {
+        Quaternion { r: self.r + other.r, i: self.i + other.i, j: self.j + other.j, k: self.k + other.k }
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            r: self.r*other.r - self.i*other.i - self.j*other.j - self.k*other.k,
+            i: self.r*other.i + self.i*other.r + self.j*other.k - self.k*other.j,
+            j: self.r*other.j - self.i*other.k + self.j*other.r + self.k*other.i,
+            k: self.r*other.k + self.i*other.j - self.j*other.i + self.k*other.r,
+        }
+    }
+}
+
+/// Where a `QuaternionJulia` slices 4D space at each time `t`.
+///
+/// A plain `Quaternion` stays put, while a closure gets the normalized time
+/// and can sweep the slice through the set over an animation.
+pub trait SlicePath: Send + Sync {
+    fn at(&self, t: f64) -> Quaternion;
+}
+
+impl SlicePath for Quaternion {
+    fn at(&self, _t: f64) -> Quaternion { *self }
+}
+
+impl<F> SlicePath for F where F: Fn(f64) -> Quaternion + Send + Sync {
+    fn at(&self, t: f64) -> Quaternion { self(t) }
+}
+
+/// `z = z^2 + c` over the quaternions, starting from the point.
+///
+/// Points are taken from `slice` with the components in `axes` replaced,
+/// the first two by the x and y of the picture, and when ray marched the
+/// third by z as well. Whatever is left over stays fixed at the value in
+/// `slice`, which can move over an animation.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_image, render_animation, render_ray_marched, ColorScheme, Fractal, DistanceEstimator, Monitor, RayMarcher};
+/// # use fractal::{Axis, Quaternion, QuaternionJulia};
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// # let monitor = Monitor::terminal();
+/// let julia = QuaternionJulia::new(Quaternion::new(-0.2, 0.8, 0.0, 0.0));
+/// render_image(julia.default_context(), &cs, &Path::new("slice.png"), julia, &monitor).unwrap();
+///
+/// let mut ctx = julia.default_context();
+/// ctx.max_iter = 200;
+/// render_ray_marched(ctx, &cs, &Path::new("3d.png"), julia, julia.default_camera(), &RayMarcher::default(), &monitor).unwrap();
+///
+/// // The j and k plane, sweeping the real part from -1 to 1
+/// let sweep = QuaternionJulia {
+///     axes: [Axis::J, Axis::K, Axis::I],
+///     ..QuaternionJulia::with_slice(julia.c, |t: f64| Quaternion::new(2.0*t - 1.0, 0.0, 0.0, 0.0))
+/// };
+/// render_animation(sweep.default_context(), cs, Path::new("frames"), 60, sweep, &monitor).unwrap();
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct QuaternionJulia<S = Quaternion> {
+    pub c: Quaternion,
+    pub axes: [Axis; 3],
+    pub slice: S,
+    /// Iterations per distance estimate when ray marched, where the
+    /// context's `max_iter` goes to the ray instead
+    pub iterations: u64,
+}
+
+impl QuaternionJulia {
+    /// The slice through the real, i and j axes with k at zero.
+    pub fn new(c: Quaternion) -> QuaternionJulia {
+        QuaternionJulia::with_slice(c, Quaternion::default())
+    }
+}
+
+impl<S: SlicePath> QuaternionJulia<S> {
+    pub fn with_slice(c: Quaternion, slice: S) -> QuaternionJulia<S> {
+        QuaternionJulia { c, axes: [Axis::R, Axis::I, Axis::J], slice, iterations: 16 }
+    }
+
+    fn point(&self, coords: &[f64], t: f64) -> Quaternion {
+        let mut z = self.slice.at(t);
+        for (&axis, &value) in self.axes.iter().zip(coords) {
+            z.set(axis, value);
+        }
+        z
+    }
+
+    fn escape_with<V: FnMut(&Quaternion)>(&self, x0: f64, y0: f64, max_iter: u64, t: f64, mut visit: V) -> EscapeResult {
+        let mut z = self.point(&[x0, y0], t);
+        let mut iter = 0;
+        while z.norm_sqr() < SMOOTH_BAILOUT && iter < max_iter {
+            z = z.square() + self.c;
+            visit(&z);
+            iter += 1;
+        }
+        let flat = (z.get(self.axes[0]), z.get(self.axes[1]));
+        if iter == max_iter { return EscapeResult::interior(max_iter, flat) }
+        EscapeResult::escaped(iter, normalized(iter, z.norm_sqr(), SMOOTH_BAILOUT, 2.0, max_iter), flat)
+    }
+}
+
+impl<S: SlicePath> Fractal for QuaternionJulia<S> {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        let mut z = self.point(&[x0, y0], t);
+        let mut iter = 0;
+        while z.norm_sqr() < 4.0 && iter < max_iter {
+            z = z.square() + self.c;
+            iter += 1;
+        }
+        iter
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        self.escape_with(x0, y0, max_iter, t, |_| {})
+    }
+
+    /// Visits the two components of the orbit that lie in the picture.
+    fn escape_visit(&self, x0: f64, y0: f64, max_iter: u64, t: f64, visit: &mut dyn FnMut(f64, f64)) -> EscapeResult {
+        let axes = self.axes;
+        self.escape_with(x0, y0, max_iter, t, |z| visit(z.get(axes[0]), z.get(axes[1])))
+    }
+
+    fn name(&self) -> &str { "quaternion julia" }
+
+    fn default_context(&self) -> RenderingContext {
+        RenderingContext {
+            x: 0.0.into(), y: 0.0.into(),
+            scale: 3.5, max_iter: 256,
+            x_px: 512, y_px: 512,
+            precision: Precision::F64,
+            strategy: Strategy::EveryPixel,
+        }
+    }
+}
+
+impl<S: SlicePath> DistanceEstimator for QuaternionJulia<S> {
+    fn distance(&self, p: Vector, t: f64) -> f64 {
+        let mut z = self.point(&[p.x, p.y, p.z], t);
+        // Only the size of the derivative is needed, which squaring doubles
+        // along with the size of z
+        let mut dr = 1.0;
+        for _ in 0..self.iterations {
+            let r2 = z.norm_sqr();
+            if r2 > 16.0 { break }
+            dr *= 2.0*r2.sqrt();
+            z = z.square() + self.c;
+        }
+        let r = z.norm_sqr().sqrt();
+        0.5*r*r.ln()/dr
+    }
+
+    fn default_camera(&self) -> Camera {
+        Camera::new(Vector::new(0.0, 1.5, -3.0), Vector::default(), 45.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Axis, Quaternion, QuaternionJulia};
+    use super::super::Fractal;
+    use super::super::formulas::Julia;
+
+    #[test]
+    fn squares() {
+        // (1 + 2i + 3j + 4k)^2 = 1 - 4 - 9 - 16 + 2(2i + 3j + 4k)
+        assert_eq!(Quaternion::new(1.0, 2.0, 3.0, 4.0).square(), Quaternion::new(-28.0, 4.0, 6.0, 8.0));
+        // i^2 = j^2 = k^2 = -1
+        assert_eq!(Quaternion::new(0.0, 1.0, 0.0, 0.0).square(), Quaternion::new(-1.0, 0.0, 0.0, 0.0));
+        assert_eq!(Quaternion::new(0.0, 0.0, 1.0, 0.0).square(), Quaternion::new(-1.0, 0.0, 0.0, 0.0));
+        assert_eq!(Quaternion::new(0.0, 0.0, 0.0, 1.0).square(), Quaternion::new(-1.0, 0.0, 0.0, 0.0));
+        assert_eq!(Quaternion::new(-3.0, 0.0, 0.0, 0.0).square(), Quaternion::new(9.0, 0.0, 0.0, 0.0));
+        for &q in &[Quaternion::new(0.5, -1.5, 2.0, 0.25), Quaternion::new(-2.0, 0.0, 1.0, -3.0)] {
+            assert_eq!(q.square(), q*q);
+        }
+    }
+
+    #[test]
+    fn components_round_trip() {
+        let mut q = Quaternion::default();
+        for (n, &axis) in [Axis::R, Axis::I, Axis::J, Axis::K].iter().enumerate() {
+            q.set(axis, n as f64 + 0.5);
+            assert_eq!(q.get(axis), n as f64 + 0.5);
+        }
+        assert_eq!(q, Quaternion::new(0.5, 1.5, 2.5, 3.5));
+    }
+
+    #[test]
+    fn flat_slices_are_julia_sets() {
+        for &(cx, cy) in &[(-0.8, 0.156), (-0.4, 0.6), (0.285, 0.01)] {
+            let julia = Julia::new(cx, cy);
+            let quaternion = QuaternionJulia::new(Quaternion::new(cx, cy, 0.0, 0.0));
+            for &(x0, y0) in &[(0.0, 0.0), (0.3, -0.2), (-1.0, 0.5), (1.2, 1.2)] {
+                let (mut expected, mut orbit) = (Vec::new(), Vec::new());
+                let a = julia.escape_visit(x0, y0, 500, 0.0, &mut |x, y| expected.push((x, y)));
+                let b = quaternion.escape_visit(x0, y0, 500, 0.0, &mut |x, y| orbit.push((x, y)));
+                assert_eq!(orbit, expected);
+                assert_eq!((b.iter, b.escaped, b.z), (a.iter, a.escaped, a.z));
+                assert_eq!(quaternion.iterate(x0, y0, 500, 0.0), julia.iterate(x0, y0, 500, 0.0));
+            }
+        }
+    }
+}
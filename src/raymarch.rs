@@ -0,0 +1,522 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::ops::{Add, Sub, Mul, Neg};
+use super::{RenderingContext, ColorScheme, Error, Monitor};
+use super::util::render_tiles;
+use image;
+use image::{ImageBuffer, Rgb};
+
+/// A point or direction in 3D.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Vector {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Vector {
+    pub fn new(x: f64, y: f64, z: f64) -> Vector {
+        Vector { x, y, z }
+    }
+
+    pub fn dot(self, other: Vector) -> f64 {
+        self.x*other.x + self.y*other.y + self.z*other.z
+    }
+
+    pub fn cross(self, other: Vector) -> Vector {
+        Vector {
+            x: self.y*other.z - self.z*other.y,
+            y: self.z*other.x - self.x*other.z,
+            z: self.x*other.y - self.y*other.x,
+        }
+    }
+
+    pub fn length(self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    /// The same direction with a length of one.
+    pub fn normalize(self) -> Vector {
+        self * (1.0 / self.length())
+    }
+}
+
+impl Add for Vector {
+    type Output = Vector;
+    fn add(self, other: Vector) -> Vector {
+        Vector { x: self.x + other.x, y: self.y + other.y, z: self.z + other.z }
+    }
+}
+
+impl Sub for Vector {
+    type Output = Vector;
+    fn sub(self, other: Vector) -> Vector {
+        Vector { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
+    }
+}
+
+impl Mul<f64> for Vector {
+    type Output = Vector;
+    fn mul(self, k: f64) -> Vector {
+        Vector { x: self.x*k, y: self.y*k, z: self.z*k }
+    }
+}
+
+impl Neg for Vector {
+    type Output = Vector;
+    fn neg(self) -> Vector {
+        Vector { x: -self.x, y: -self.y, z: -self.z }
+    }
+}
+
+/// Where the picture is taken from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Vector,
+    pub look_at: Vector,
+    /// Roughly which way is up in the picture
+    pub up: Vector,
+    /// Vertical field of view in degrees
+    pub fov: f64,
+}
+
+impl Camera {
+    /// A camera at `position` pointed at `look_at` with y up.
+    pub fn new(position: Vector, look_at: Vector, fov: f64) -> Camera {
+        Camera { position, look_at, up: Vector::new(0.0, 1.0, 0.0), fov }
+    }
+
+    /// Checks that the camera looks somewhere, which it can't from the point
+    /// it is looking at, or with `up` along the way it looks.
+    pub fn validate(&self) -> Result<(), Error> {
+        let right = (self.look_at - self.position).cross(self.up);
+        // Also catches coordinates that aren't numbers
+        if right.length() > 0.0 { Ok(()) } else { Err(Error::Camera) }
+    }
+
+    /// Direction of the ray through the middle of pixel `(x_px, y_px)`.
+    fn ray(&self, x_px: u32, y_px: u32, width: u32, height: u32) -> Vector {
+        let forward = (self.look_at - self.position).normalize();
+        let right = forward.cross(self.up).normalize();
+        let up = right.cross(forward);
+        let half = (self.fov.to_radians() / 2.0).tan();
+        let u = (2.0*(x_px as f64 + 0.5)/width as f64 - 1.0) * half * width as f64/height as f64;
+        let v = (1.0 - 2.0*(y_px as f64 + 0.5)/height as f64) * half;
+        (forward + right*u + up*v).normalize()
+    }
+}
+
+/// Where a ray marched animation gets its camera from each frame.
+///
+/// A plain `Camera` stays put, while a closure gets the normalized time `t`
+/// and can fly the camera around.
+pub trait CameraPath: Send + Sync {
+    fn at(&self, t: f64) -> Camera;
+}
+
+impl CameraPath for Camera {
+    fn at(&self, _t: f64) -> Camera { *self }
+}
+
+impl<F> CameraPath for F where F: Fn(f64) -> Camera + Send + Sync {
+    fn at(&self, t: f64) -> Camera { self(t) }
+}
+
+/// A 3D fractal described by a lower bound on the distance to its surface.
+pub trait DistanceEstimator: Send + Sync {
+    /// How far `p` is from the surface at least, at normalized time `t`.
+    /// Anything not much more than zero counts as on the surface.
+    fn distance(&self, p: Vector, t: f64) -> f64;
+
+    /// A camera that fits the whole fractal in the picture.
+    fn default_camera(&self) -> Camera {
+        Camera::new(Vector::new(0.0, 0.0, -3.0), Vector::default(), 45.0)
+    }
+}
+
+/// The power `n` Mandelbulb, using the spherical coordinates formula.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mandelbulb {
+    pub power: f64,
+    pub iterations: u32,
+    pub bailout: f64,
+}
+
+impl Mandelbulb {
+    pub fn new(power: f64) -> Mandelbulb {
+        Mandelbulb { power, iterations: 16, bailout: 2.0 }
+    }
+}
+
+impl DistanceEstimator for Mandelbulb {
+    fn distance(&self, p: Vector, _t: f64) -> f64 {
+        let n = self.power;
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = z.length();
+        for _ in 0..self.iterations {
+            if r > self.bailout { break }
+            if r > 0.0 {
+                let theta = (z.z / r).acos() * n;
+                let phi = z.y.atan2(z.x) * n;
+                dr = r.powf(n - 1.0)*n*dr + 1.0;
+                let zr = r.powf(n);
+                z = Vector::new(theta.sin()*phi.cos(), theta.sin()*phi.sin(), theta.cos())*zr + p;
+            } else {
+                z = p;
+            }
+            r = z.length();
+        }
+        if r == 0.0 { return 0.0 }
+        0.5*r.ln()*r/dr
+    }
+
+    fn default_camera(&self) -> Camera {
+        Camera::new(Vector::new(0.0, 1.2, -2.4), Vector::default(), 45.0)
+    }
+}
+
+/// The Mandelbox: a box fold and a sphere fold followed by scaling by `scale`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mandelbox {
+    pub scale: f64,
+    /// Points closer to the origin than this are scaled up by a constant
+    pub min_radius: f64,
+    /// Points closer to the origin than this are inverted in a sphere of this radius
+    pub fixed_radius: f64,
+    pub iterations: u32,
+}
+
+impl Mandelbox {
+    pub fn new(scale: f64) -> Mandelbox {
+        Mandelbox { scale, min_radius: 0.5, fixed_radius: 1.0, iterations: 16 }
+    }
+}
+
+impl DistanceEstimator for Mandelbox {
+    fn distance(&self, p: Vector, _t: f64) -> f64 {
+        let fold = |v: f64| v.clamp(-1.0, 1.0)*2.0 - v;
+        let min2 = self.min_radius*self.min_radius;
+        let fixed2 = self.fixed_radius*self.fixed_radius;
+        let mut z = p;
+        let mut dr = 1.0;
+        for _ in 0..self.iterations {
+            z = Vector::new(fold(z.x), fold(z.y), fold(z.z));
+            let r2 = z.dot(z);
+            let k = if r2 < min2 { fixed2/min2 } else if r2 < fixed2 { fixed2/r2 } else { 1.0 };
+            z = z*(k*self.scale) + p;
+            dr = dr*k*self.scale.abs() + 1.0;
+        }
+        z.length()/dr.abs()
+    }
+
+    fn default_camera(&self) -> Camera {
+        // Scales past one spread out to 4(s + 1)/(s - 1) across, the rest
+        // stay about 4 across
+        let size = if self.scale > 1.0 { 2.0*(self.scale + 1.0)/(self.scale - 1.0) } else { 2.0 };
+        Camera::new(Vector::new(0.0, 1.5*size, -4.5*size), Vector::default(), 45.0)
+    }
+}
+
+/// Settings for `render_ray_marched`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayMarcher {
+    /// Direction the light comes from
+    pub light: Vector,
+    /// Color of rays that miss
+    pub background: Rgb<u8>,
+    /// Rays that get this far from the camera miss
+    pub max_distance: f64,
+    /// Hardness of the shadows, bigger is sharper. `None` turns them off.
+    pub shadows: Option<f64>,
+    /// How far out ambient occlusion looks for nearby surfaces. `None` turns it off.
+    pub occlusion: Option<f64>,
+}
+
+impl Default for RayMarcher {
+    fn default() -> RayMarcher {
+        RayMarcher {
+            light: Vector::new(-0.5, 1.0, -0.7),
+            background: Rgb([0, 0, 0]),
+            max_distance: 100.0,
+            shadows: Some(16.0),
+            occlusion: Some(0.1),
+        }
+    }
+}
+
+impl RayMarcher {
+    /// Marches the ray through pixel `(x_px, y_px)`. Hits give a position in
+    /// the `ColorScheme` and a brightness, both from 0 to 1.
+    fn trace<D: DistanceEstimator + ?Sized>(&self, de: &D, camera: &Camera, ctx: &RenderingContext, x_px: u32, y_px: u32, t: f64) -> Option<(f64, f64)> {
+        let dir = camera.ray(x_px, y_px, ctx.x_px, ctx.y_px);
+        // Stop once the surface is closer than about half a pixel
+        let pixel = (camera.fov.to_radians() / 2.0).tan() / ctx.y_px as f64;
+
+        let mut travelled = 0.0;
+        let mut steps = 0;
+        let mut epsilon = 0.0;
+        loop {
+            if steps == ctx.max_iter { break }
+            let d = de.distance(camera.position + dir*travelled, t);
+            epsilon = (pixel*travelled).max(1e-9);
+            if d < epsilon { break }
+            travelled += d;
+            steps += 1;
+            if travelled > self.max_distance { return None }
+        }
+
+        let p = camera.position + dir*travelled;
+        let normal = Self::normal(de, p, epsilon, t);
+        let light = self.light.normalize();
+        let diffuse = normal.dot(light).max(0.0);
+        let shadow = match self.shadows {
+            Some(k) if diffuse > 0.0 => self.shadow(de, p + normal*(2.0*epsilon), light, k, ctx.max_iter, t),
+            _ => 1.0,
+        };
+        let occlusion = match self.occlusion {
+            Some(radius) => Self::occlusion(de, p, normal, radius, t),
+            None => 1.0,
+        };
+
+        let position = (steps as f64 / ctx.max_iter as f64).sqrt();
+        Some((position, occlusion*(0.2 + 0.8*diffuse*shadow)))
+    }
+
+    /// Surface normal from the gradient of the distance, sampled at the
+    /// corners of a tetrahedron `h` across.
+    fn normal<D: DistanceEstimator + ?Sized>(de: &D, p: Vector, h: f64, t: f64) -> Vector {
+        let corners = [
+            Vector::new(1.0, -1.0, -1.0), Vector::new(-1.0, -1.0, 1.0),
+            Vector::new(-1.0, 1.0, -1.0), Vector::new(1.0, 1.0, 1.0),
+        ];
+        corners.iter().fold(Vector::default(), |n, &k| n + k*de.distance(p + k*h, t)).normalize()
+    }
+
+    /// How much light gets from `light` to `p`, darkening the closer the
+    /// shadow ray passes by the surface on its way out.
+    fn shadow<D: DistanceEstimator + ?Sized>(&self, de: &D, p: Vector, light: Vector, hardness: f64, max_steps: u64, t: f64) -> f64 {
+        let mut lit : f64 = 1.0;
+        let mut travelled = 0.0;
+        for _ in 0..max_steps {
+            let d = de.distance(p + light*travelled, t);
+            if d < 1e-9 + travelled*1e-4 { return 0.0 }
+            if travelled > 0.0 { lit = lit.min(hardness*d/travelled) }
+            travelled += d;
+            if travelled > self.max_distance { break }
+        }
+        lit.clamp(0.0, 1.0)
+    }
+
+    /// One for open surfaces, falling towards zero in creases, from how
+    /// close the surface gets to points along the normal.
+    fn occlusion<D: DistanceEstimator + ?Sized>(de: &D, p: Vector, normal: Vector, radius: f64, t: f64) -> f64 {
+        let mut occluded = 0.0;
+        let mut weight = 1.0;
+        let mut total = 0.0;
+        for i in 1..6 {
+            let h = radius*i as f64/5.0;
+            occluded += weight*(h - de.distance(p + normal*h, t)).max(0.0)/h;
+            total += weight;
+            weight *= 0.6;
+        }
+        (1.0 - occluded/total).clamp(0.0, 1.0)
+    }
+
+    fn render<D: DistanceEstimator + ?Sized + 'static>(&self, ctx: &RenderingContext, cs: &ColorScheme, de: &Arc<D>, camera: Camera, t: f64, monitor: &Monitor) -> Result<image::RgbImage, Error> {
+        camera.validate()?;
+        let trace_ctx = ctx.clone();
+        let de = de.clone();
+        let marcher = *self;
+        let hits = render_tiles::<f64, _, _>(ctx, monitor, move |_, _, x_px, y_px| {
+            marcher.trace(&*de, &camera, &trace_ctx, x_px, y_px, t)
+        })?;
+
+        let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = match hits[x as usize + y as usize*ctx.x_px as usize] {
+                Some((position, brightness)) => {
+                    let color = cs.get_color(position).data;
+                    Rgb([
+                        (color[0] as f64*brightness) as u8,
+                        (color[1] as f64*brightness) as u8,
+                        (color[2] as f64*brightness) as u8,
+                    ])
+                },
+                None => self.background,
+            };
+        }
+        Ok(img)
+    }
+}
+
+/// Ray marches a 3D fractal from `camera`.
+///
+/// Only the size of `ctx` is used for the picture, and `ctx.max_iter` is
+/// how many steps a ray gets to find the surface. Surfaces are colored from
+/// `cs` by how many steps that took, so creases and edges that rays had to
+/// creep past stand out, and shaded by `marcher`.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_ray_marched, ColorScheme, DistanceEstimator, Fractal, Mandelbulb, Monitor, RayMarcher};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// # let mut ctx = Mandelbrot.default_context();
+/// let bulb = Mandelbulb::new(8.0);
+/// ctx.max_iter = 200;
+/// render_ray_marched(ctx, &cs, &Path::new("bulb.png"), bulb, bulb.default_camera(), &RayMarcher::default(), &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+pub fn render_ray_marched<D>(ctx: RenderingContext, cs: &ColorScheme, path: &Path, de: D, camera: Camera, marcher: &RayMarcher, monitor: &Monitor) -> Result<(), Error>
+        where D: DistanceEstimator + 'static {
+    image::ImageRgb8(render_ray_marched_rgb(ctx, cs, de, camera, marcher, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_ray_marched` without the file, handing back the picture instead.
+pub fn render_ray_marched_rgb<D>(ctx: RenderingContext, cs: &ColorScheme, de: D, camera: Camera, marcher: &RayMarcher, monitor: &Monitor) -> Result<image::RgbImage, Error>
+        where D: DistanceEstimator + 'static {
+    cs.validate()?;
+    marcher.render(&ctx, cs, &Arc::new(de), camera, 0.0, monitor)
+}
+
+/// Renders `frames` pictures into the folder `path` with the camera taken
+/// from `camera` at each step of the animation.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_ray_marched_animation, Camera, ColorScheme, Fractal, Mandelbox, Monitor, RayMarcher, Vector};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// # let ctx = Mandelbrot.default_context();
+/// // Once around the Mandelbox
+/// let orbit = |t: f64| {
+///     let angle = t*2.0*std::f64::consts::PI;
+///     Camera::new(Vector::new(27.0*angle.sin(), 9.0, -27.0*angle.cos()), Vector::default(), 45.0)
+/// };
+/// render_ray_marched_animation(ctx, &cs, Path::new("frames"), 120, Mandelbox::new(2.0), orbit, &RayMarcher::default(), &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+#[allow(clippy::too_many_arguments)]
+pub fn render_ray_marched_animation<D, C>(ctx: RenderingContext, cs: &ColorScheme, path: &Path, frames: u32, de: D, camera: C, marcher: &RayMarcher, monitor: &Monitor) -> Result<(), Error>
+        where D: DistanceEstimator + 'static, C: CameraPath {
+    render_ray_marched_animation_with(ctx, cs, frames, de, camera, marcher, monitor, |frame, img| {
+        image::ImageRgb8(img).save(path.join(Path::new(&format!("frame{}.png", frame))))?;
+        Ok(())
+    })
+}
+
+/// `render_ray_marched_animation` handing each frame and its number to
+/// `sink` in order instead of writing files. Frames keep coming after
+/// `sink` fails, and the first error it gave is returned at the end.
+#[allow(clippy::too_many_arguments)]
+pub fn render_ray_marched_animation_with<D, C, S>(ctx: RenderingContext, cs: &ColorScheme, frames: u32, de: D, camera: C, marcher: &RayMarcher, monitor: &Monitor, mut sink: S) -> Result<(), Error>
+        where D: DistanceEstimator + 'static, C: CameraPath, S: FnMut(u32, image::RgbImage) -> Result<(), Error> {
+    cs.validate()?;
+    let de = Arc::new(de);
+    let mut failed = Ok(());
+    for frame in 0..frames {
+        let t = frame as f64 / frames as f64;
+        if let Err(err) = sink(frame, marcher.render(&ctx, cs, &de, camera.at(t), t, monitor)?) {
+            if failed.is_ok() { failed = Err(err); }
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Camera, DistanceEstimator, Mandelbox, Mandelbulb, RayMarcher, Vector, render_ray_marched_rgb};
+    use super::super::{ColorScheme, Error, Fractal, Monitor, RenderingContext};
+    use super::super::formulas::Mandelbrot;
+
+    /// Points at `radius` from the origin in a spread of directions.
+    fn sphere(radius: f64) -> Vec<Vector> {
+        let directions = [
+            (1.0, 0.0, 0.0), (-1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, -1.0, 0.0), (0.0, 0.0, 1.0), (0.0, 0.0, -1.0),
+            (1.0, 1.0, 1.0), (-1.0, 2.0, 0.5), (0.3, -0.7, -1.0), (-2.0, -1.0, 3.0),
+        ];
+        directions.iter().map(|&(x, y, z)| Vector::new(x, y, z).normalize()*radius).collect()
+    }
+
+    #[test]
+    fn mandelbulb_distances() {
+        let bulb = Mandelbulb::new(8.0);
+        // Everything is within 1.2 of the origin
+        for &radius in &[1.5, 2.0, 4.0] {
+            for p in sphere(radius) {
+                let d = bulb.distance(p, 0.0);
+                assert!(d > 0.0 && d < radius, "{} at {:?}", d, p);
+            }
+        }
+        // Along the z axis the orbit is x -> x^8 + c, which escapes from
+        // where x^8 + c just touches x, at x = 8^(-1/7)
+        let x = 8f64.powf(-1.0/7.0);
+        let surface = x - x.powi(8);
+        assert!(bulb.distance(Vector::new(0.0, 0.0, surface), 0.0).abs() < 0.02);
+        let d = bulb.distance(Vector::new(0.0, 0.0, surface + 0.05), 0.0);
+        assert!(d > 0.0 && d < 0.05);
+        assert_eq!(bulb.distance(Vector::default(), 0.0), 0.0);
+    }
+
+    #[test]
+    fn mandelbox_distances() {
+        let mandelbox = Mandelbox::new(2.0);
+        // Scale 2 fills the cube 6 out from the origin
+        for &radius in &[11.0, 20.0, 100.0] {
+            for p in sphere(radius) {
+                let d = mandelbox.distance(p, 0.0);
+                assert!(d > 0.0 && d < radius, "{} at {:?}", d, p);
+            }
+        }
+        // (6, 0, 0) folds to (-2, 0, 0) and back, never getting away
+        assert!(mandelbox.distance(Vector::new(6.0, 0.0, 0.0), 0.0) < 1e-6);
+        let d = mandelbox.distance(Vector::new(6.5, 0.0, 0.0), 0.0);
+        assert!(d > 0.0 && d < 0.5);
+    }
+
+    fn small() -> RenderingContext {
+        let mut ctx = Mandelbrot.default_context();
+        ctx.x_px = 9;
+        ctx.y_px = 9;
+        ctx.max_iter = 200;
+        ctx
+    }
+
+    #[test]
+    fn rays_hit_what_the_camera_looks_at() {
+        let ctx = small();
+        let marcher = RayMarcher::default();
+        let bulb = Mandelbulb::new(8.0);
+        let mandelbox = Mandelbox::new(2.0);
+        assert!(marcher.trace(&bulb, &bulb.default_camera(), &ctx, 4, 4, 0.0).is_some());
+        assert!(marcher.trace(&mandelbox, &mandelbox.default_camera(), &ctx, 4, 4, 0.0).is_some());
+
+        let away = Camera::new(Vector::new(0.0, 0.0, -3.0), Vector::new(0.0, 0.0, -4.0), 45.0);
+        for y in 0..ctx.y_px {
+            for x in 0..ctx.x_px {
+                assert_eq!(marcher.trace(&bulb, &away, &ctx, x, y, 0.0), None);
+            }
+        }
+    }
+
+    #[test]
+    fn cameras_need_a_direction() {
+        let position = Vector::new(1.0, 2.0, 3.0);
+        assert!(Camera::new(position, Vector::default(), 45.0).validate().is_ok());
+        let nowhere = Camera::new(position, position, 45.0);
+        assert!(matches!(nowhere.validate(), Err(Error::Camera)));
+        let down = Camera::new(position, Vector::new(1.0, -2.0, 3.0), 45.0);
+        assert!(matches!(down.validate(), Err(Error::Camera)));
+
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0xffffff, 0.0);
+        let result = render_ray_marched_rgb(small(), &cs, Mandelbulb::new(8.0), nowhere, &RayMarcher::default(), &Monitor::new());
+        assert!(matches!(result, Err(Error::Camera)));
+    }
+}
@@ -0,0 +1,181 @@
+use std::ops::{Add, Sub, Mul, Div, Neg};
+use super::BigFloat;
+
+/// A real number type formulas can be iterated in.
+///
+/// Implemented for `f64`, `DoubleDouble` and `BigFloat`, which trade speed
+/// for how deep a zoom they can resolve.
+pub trait Real: Clone + PartialOrd + Send + Sync + 'static
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Neg<Output = Self> {
+    fn from_f64(v: f64) -> Self;
+    fn from_big(v: &BigFloat) -> Self;
+    fn to_f64(&self) -> f64;
+    fn to_big(&self) -> BigFloat;
+    /// Gap between 1 and the next number that can be told apart from it.
+    fn epsilon(&self) -> f64;
+
+    fn abs(self) -> Self {
+        if self < Self::from_f64(0.0) { -self } else { self }
+    }
+}
+
+impl Real for f64 {
+    fn from_f64(v: f64) -> f64 { v }
+    fn from_big(v: &BigFloat) -> f64 { v.to_f64() }
+    fn to_f64(&self) -> f64 { *self }
+    fn to_big(&self) -> BigFloat { BigFloat::from(*self) }
+    fn epsilon(&self) -> f64 { f64::EPSILON }
+    fn abs(self) -> f64 { f64::abs(self) }
+}
+
+impl Real for BigFloat {
+    fn from_f64(v: f64) -> BigFloat { BigFloat::from(v) }
+    fn from_big(v: &BigFloat) -> BigFloat { v.clone() }
+    fn to_f64(&self) -> f64 { BigFloat::to_f64(self) }
+    fn to_big(&self) -> BigFloat { self.clone() }
+    fn epsilon(&self) -> f64 { 0.5f64.powi(self.precision() as i32 - 1) }
+}
+
+/// A complex number over any `Real`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complex<T> {
+    pub re: T,
+    pub im: T,
+}
+
+impl<T: Real> Complex<T> {
+    pub fn new(re: T, im: T) -> Complex<T> {
+        Complex { re, im }
+    }
+
+    pub fn from_f64(re: f64, im: f64) -> Complex<T> {
+        Complex { re: T::from_f64(re), im: T::from_f64(im) }
+    }
+
+    pub fn to_f64(&self) -> (f64, f64) {
+        (self.re.to_f64(), self.im.to_f64())
+    }
+
+    /// `|z|^2`
+    pub fn norm_sqr(&self) -> T {
+        self.re.clone()*self.re.clone() + self.im.clone()*self.im.clone()
+    }
+
+    pub fn conj(self) -> Complex<T> {
+        Complex { re: self.re, im: -self.im }
+    }
+
+    pub fn square(&self) -> Complex<T> {
+        let (x, y) = (self.re.clone(), self.im.clone());
+        Complex {
+            re: x.clone()*x.clone() - y.clone()*y.clone(),
+            im: T::from_f64(2.0)*x*y,
+        }
+    }
+}
+
+impl<T: Real> Add for Complex<T> {
+    type Output = Complex<T>;
+    fn add(self, other: Complex<T>) -> Complex<T> {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+}
+
+impl<T: Real> Sub for Complex<T> {
+    type Output = Complex<T>;
+    fn sub(self, other: Complex<T>) -> Complex<T> {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+}
+
+impl<T: Real> Mul for Complex<T> {
+    type Output = Complex<T>;
+    fn mul(self, other: Complex<T>) -> Complex<T> {
+        Complex {
+            re: self.re.clone()*other.re.clone() - self.im.clone()*other.im.clone(),
+            im: self.re*other.im + self.im*other.re,
+        }
+    }
+}
+
+/// Only `f64` can divide, `BigFloat` has no division.
+impl Div for Complex<f64> {
+    type Output = Complex<f64>;
+    fn div(self, other: Complex<f64>) -> Complex<f64> {
+        let d = other.re*other.re + other.im*other.im;
+        Complex {
+            re: (self.re*other.re + self.im*other.im)/d,
+            im: (self.im*other.re - self.re*other.im)/d,
+        }
+    }
+}
+
+/// Functions only `f64` has.
+impl Complex<f64> {
+    /// `|z|`
+    pub fn norm(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    /// Principal square root, with a non-negative real part.
+    pub fn sqrt(self) -> Complex<f64> {
+        let r = self.norm();
+        Complex::new(((r + self.re) / 2.0).sqrt(), ((r - self.re) / 2.0).sqrt().copysign(self.im))
+    }
+
+    pub fn exp(self) -> Complex<f64> {
+        let r = self.re.exp();
+        Complex::new(r*self.im.cos(), r*self.im.sin())
+    }
+
+    /// Principal natural logarithm.
+    pub fn ln(self) -> Complex<f64> {
+        Complex::new(self.norm().ln(), self.arg())
+    }
+
+    pub fn sin(self) -> Complex<f64> {
+        Complex::new(self.re.sin()*self.im.cosh(), self.re.cos()*self.im.sinh())
+    }
+
+    pub fn cos(self) -> Complex<f64> {
+        Complex::new(self.re.cos()*self.im.cosh(), -self.re.sin()*self.im.sinh())
+    }
+
+    pub fn sinh(self) -> Complex<f64> {
+        Complex::new(self.re.sinh()*self.im.cos(), self.re.cosh()*self.im.sin())
+    }
+
+    pub fn cosh(self) -> Complex<f64> {
+        Complex::new(self.re.cosh()*self.im.cos(), self.re.sinh()*self.im.sin())
+    }
+
+    /// `z^n` by repeated squaring.
+    pub fn powi(self, n: i32) -> Complex<f64> {
+        let mut result = Complex::new(1.0, 0.0);
+        let mut base = self;
+        let mut e = n.unsigned_abs();
+        while e > 0 {
+            if e & 1 == 1 { result = result*base; }
+            base = base*base;
+            e >>= 1;
+        }
+        if n < 0 { Complex::new(1.0, 0.0)/result } else { result }
+    }
+
+    /// Principal value of `z^w`, with `0^w = 0`.
+    pub fn powc(self, w: Complex<f64>) -> Complex<f64> {
+        if self.re == 0.0 && self.im == 0.0 { return self }
+        (self.ln()*w).exp()
+    }
+}
+
+impl<T: Real> Neg for Complex<T> {
+    type Output = Complex<T>;
+    fn neg(self) -> Complex<T> {
+        Complex { re: -self.re, im: -self.im }
+    }
+}
@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use image::{RgbImage, Pixel};
+use super::{Fractal, EscapeResult, RenderingContext};
+
+/// A shape orbits are measured against, in fractal coordinates.
+#[derive(Clone, Debug)]
+pub enum Trap {
+    Point { x: f64, y: f64 },
+    /// Line through `(x, y)` at `angle` radians from the real axis
+    Line { x: f64, y: f64, angle: f64 },
+    /// The horizontal and vertical lines through `(x, y)`
+    Cross { x: f64, y: f64 },
+    Circle { x: f64, y: f64, radius: f64 },
+    /// An image centered on `(x, y)` and `width` wide. The distance is one
+    /// minus the brightness of the pixel `z` lands on, so bright parts of the
+    /// image attract, and anywhere off the image, or any point at all when
+    /// it has no pixels, is infinitely far away.
+    Image { image: Arc<RgbImage>, x: f64, y: f64, width: f64 },
+}
+
+impl Trap {
+    /// Distance from `z` to the trap.
+    pub fn distance(&self, zx: f64, zy: f64) -> f64 {
+        match *self {
+            Trap::Point { x, y } => (zx - x).hypot(zy - y),
+            Trap::Line { x, y, angle } => ((zx - x)*angle.sin() - (zy - y)*angle.cos()).abs(),
+            Trap::Cross { x, y } => (zx - x).abs().min((zy - y).abs()),
+            Trap::Circle { x, y, radius } => ((zx - x).hypot(zy - y) - radius).abs(),
+            Trap::Image { ref image, x, y, width } => {
+                let (w, h) = image.dimensions();
+                if w == 0 || h == 0 { return f64::INFINITY }
+                let height = width * h as f64 / w as f64;
+                let px = ((zx - x)/width + 0.5)*w as f64;
+                let py = ((zy - y)/height + 0.5)*h as f64;
+                if px < 0.0 || py < 0.0 || px >= w as f64 || py >= h as f64 { return f64::INFINITY }
+                let luma = image.get_pixel(px as u32, py as u32).to_luma()[0];
+                1.0 - luma as f64 / 255.0
+            },
+        }
+    }
+}
+
+/// Where a `Trapped` fractal gets its trap from each frame.
+///
+/// A plain `Trap` stays put, while a closure gets the normalized time `t`
+/// and can move or reshape the trap over an animation.
+pub trait TrapSource: Send + Sync {
+    fn at(&self, t: f64) -> Cow<'_, Trap>;
+}
+
+impl TrapSource for Trap {
+    fn at(&self, _t: f64) -> Cow<'_, Trap> { Cow::Borrowed(self) }
+}
+
+impl<F> TrapSource for F where F: Fn(f64) -> Trap + Send + Sync {
+    fn at(&self, t: f64) -> Cow<'_, Trap> { Cow::Owned(self(t)) }
+}
+
+/// Wraps a fractal so every `EscapeResult` carries the closest its orbit
+/// came to `trap`, for `Coloring::Trap`.
+///
+/// Orbits are followed in `f64` whatever the precision of the render, and
+/// formulas that keep the default `escape_visit` never get near the trap.
+///
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_image, render_animation, Coloring, ColorScheme, Fractal, Monitor, Trap, Trapped};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// let mut cs = ColorScheme::new();
+/// cs.set_coloring(Coloring::Trap { scale: 0.1 });
+/// let ctx = Mandelbrot.default_context();
+/// let cross = Trapped::new(Mandelbrot, Trap::Cross { x: 0.0, y: 0.0 });
+/// render_image(ctx.clone(), &cs, &Path::new("cross.png"), cross, &Monitor::terminal()).unwrap();
+///
+/// // A circle that grows over the animation
+/// let circle = Trapped::new(Mandelbrot, |t: f64| Trap::Circle { x: 0.0, y: 0.0, radius: t });
+/// render_animation(ctx, cs, Path::new("frames"), 60, circle, &Monitor::terminal()).unwrap();
+/// # }
+/// ```
+pub struct Trapped<F, P> {
+    pub frac: F,
+    pub trap: P,
+}
+
+impl<F: Fractal, P: TrapSource> Trapped<F, P> {
+    pub fn new(frac: F, trap: P) -> Trapped<F, P> {
+        Trapped { frac, trap }
+    }
+}
+
+impl<F: Fractal, P: TrapSource> Fractal for Trapped<F, P> {
+    fn iterate(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> u64 {
+        self.frac.iterate(x0, y0, max_iter, t)
+    }
+
+    fn escape(&self, x0: f64, y0: f64, max_iter: u64, t: f64) -> EscapeResult {
+        let trap = self.trap.at(t);
+        let mut closest = f64::INFINITY;
+        let mut result = self.frac.escape_visit(x0, y0, max_iter, t, &mut |x, y| {
+            closest = closest.min(trap.distance(x, y));
+        });
+        result.trap = Some(closest);
+        result
+    }
+
+    fn name(&self) -> &str { self.frac.name() }
+
+    fn default_context(&self) -> RenderingContext { self.frac.default_context() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_PI_4;
+    use std::sync::Arc;
+    use image::{RgbImage, Rgb};
+    use super::Trap;
+
+    fn close(a: f64, b: f64) -> bool { (a - b).abs() < 1e-12 }
+
+    #[test]
+    fn shape_distances() {
+        let point = Trap::Point { x: 1.0, y: -1.0 };
+        assert!(close(point.distance(4.0, 3.0), 5.0));
+        assert!(close(point.distance(1.0, -1.0), 0.0));
+
+        let diagonal = Trap::Line { x: 1.0, y: 1.0, angle: FRAC_PI_4 };
+        assert!(close(diagonal.distance(-2.0, -2.0), 0.0));
+        assert!(close(diagonal.distance(1.0, 0.0), 0.5f64.sqrt()));
+        let horizontal = Trap::Line { x: 0.0, y: 0.5, angle: 0.0 };
+        assert!(close(horizontal.distance(7.0, -1.5), 2.0));
+
+        let cross = Trap::Cross { x: 1.0, y: 2.0 };
+        assert!(close(cross.distance(1.25, 5.0), 0.25));
+        assert!(close(cross.distance(-3.0, 1.5), 0.5));
+        assert!(close(cross.distance(9.0, 2.0), 0.0));
+
+        let circle = Trap::Circle { x: 0.0, y: 1.0, radius: 2.0 };
+        assert!(close(circle.distance(0.0, 1.0), 2.0));
+        assert!(close(circle.distance(3.0, 5.0), 3.0));
+        assert!(close(circle.distance(2.0, 1.0), 0.0));
+    }
+
+    #[test]
+    fn image_lookup() {
+        // Black but for a white top right and a gray bottom left pixel
+        let mut image = RgbImage::new(2, 2);
+        image.put_pixel(1, 0, Rgb([255, 255, 255]));
+        image.put_pixel(0, 1, Rgb([51, 51, 51]));
+        let trap = Trap::Image { image: Arc::new(image), x: 1.0, y: 1.0, width: 2.0 };
+        assert!(close(trap.distance(1.5, 0.5), 0.0));
+        assert!(close(trap.distance(0.5, 0.5), 1.0));
+        assert!(close(trap.distance(0.5, 1.5), 0.8));
+        assert_eq!(trap.distance(2.5, 1.0), f64::INFINITY);
+        assert_eq!(trap.distance(1.0, -0.5), f64::INFINITY);
+    }
+
+    #[test]
+    fn empty_images_are_never_reached() {
+        for &(w, h) in &[(0, 0), (0, 4), (4, 0)] {
+            let trap = Trap::Image { image: Arc::new(RgbImage::new(w, h)), x: 0.0, y: 0.0, width: 1.0 };
+            assert_eq!(trap.distance(0.0, 0.0), f64::INFINITY);
+        }
+    }
+}
@@ -1,260 +1,593 @@
 use std::path::Path;
 use std::thread;
 use std::sync::{Arc, Mutex};
-use super::{RenderingContext, ColorScheme};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use super::{RenderingContext, ColorScheme, Fractal, IntoFractal, EscapeResult, Precision, Real, BigFloat, DoubleDouble, Error, Monitor, Phase, Strategy};
+use super::context::Points;
+use super::error::join;
 use num_cpus;
 use spmc;
 use image;
-use pbr::ProgressBar;
 use image::ImageBuffer;
 
-///    render_image(ctx, &cs, &Path::new("test.png"), |x0, y0, max_iter| {
-///            let mut x = 0.0;
-///            let mut y = 0.0;
-///            let mut iter = 0;
-///
-///            while x*x + y*y < 4.0 && iter < max_iter {
-///                let xtemp = x*x - y*y + x0;
-///                let ytemp = 2.0*x*y + y0;
-///
-///                if x == xtemp && y == ytemp {
-///                    iter = max_iter;
-///                    break;
-///                }
-///
-///                x = xtemp;
-///                y = ytemp;
-///                iter += 1;
-///            }
-///
-///            iter
-///        });
-///
-///    render_image(ctx, &cs, &Path::new("test.png"), |x0, y0, max_iter| {
-///            let mut x = x0;
-///            let mut y = y0;
-///            let cx = 0.0;
-///            let cy = 0.90;
-///            let mut iter = 0;
-///
-///            while x*x + y*y < 4.0 && iter < max_iter {
-///                let xtemp = x*x - y*y;
-///                y = 2.0*x*y + cy;
-///                x = xtemp + cx;
-///                iter += 1;
-///            }
-///
-///            iter
-///        });
-///
-///    render_image(ctx, &cs, &Path::new("test.png"), |x0, y0, max_iter| {
-///            let mut x = x0;
-///            let mut y = y0;
-///            let cx = 1.0;
-///            let cy = 1.0;
-///            let mut iter = 0;
-///    
-///            while y.abs() < 50.0 && iter < max_iter {
-///                let xtemp = x.sin()*y.cosh();
-///                let ytemp = x.cos()*y.sinh();
-///                x = cx*xtemp - cy*ytemp;
-///                y = cx*ytemp + cy*xtemp;
-///                iter += 1;
-///            }
-///    
-///            iter
-///        });
-
-pub fn render_image<F>(ctx: RenderingContext, cs: &ColorScheme, path: &Path, frac: F) where F: Fn(f64, f64, u64) -> u64 + Send + Sync + 'static{
-    let mut iters : Vec<Arc<Mutex<Vec<u64>>>> = Vec::with_capacity(ctx.y_px as usize);
-    for _ in 0..ctx.y_px {
-        let mut row : Vec<u64> = Vec::with_capacity(ctx.x_px as usize);
-        for _ in 0..ctx.x_px {
-            row.push(0);
-        }
-        iters.push(Arc::new(Mutex::new(row)));
-    }
+/// How the `EscapeResult` of an escaped point is turned into a position in a `ColorScheme`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Coloring {
+    /// Smooth iteration count, histogram equalized over the whole image
+    #[default]
+    Histogram,
+    /// Smooth iteration count divided by `max_iter`
+    Linear,
+    /// Argument of the final `z`
+    Angle,
+    /// Estimated distance to the set. Points within `threshold` pixels of it
+    /// get the start of the `ColorScheme` and the rest fade towards the end
+    /// with distance, which keeps filaments thinner than a pixel visible.
+    /// Formulas that don't track the derivative come out as far away.
+    Distance { threshold: f64 },
+    /// Hue picked by the root a root finding formula converged to, darker
+    /// the longer that took. Points that stop without settling on a root,
+    /// like every point of a Nova fractal, are shaded the same way along the
+    /// `ColorScheme` instead.
+    Root,
+    /// Closest the orbit came to the trap of a `Trapped` fractal, running
+    /// from the start of the `ColorScheme` on the trap to the end `scale`
+    /// or so away from it.
+    Trap { scale: f64 },
+}
 
-    let threads = num_cpus::get();
-    let mut histograms : Vec<Arc<Mutex<Vec<u64>>>> = Vec::with_capacity(threads);
-    for _ in 0..threads {
-        let mut hist = Vec::with_capacity(ctx.max_iter as usize);
-        for _ in 0..ctx.max_iter { hist.push(0); }
-        histograms.push(Arc::new(Mutex::new(hist)));
+impl Coloring {
+    /// The value stored for a pixel, or `None` if it is inside the set.
+    pub fn value(&self, result: &EscapeResult, ctx: &RenderingContext) -> Option<f64> {
+        if !result.escaped { return None }
+        let max_iter = ctx.max_iter;
+        Some(match *self {
+            Coloring::Histogram => result.smooth,
+            Coloring::Linear => result.smooth / max_iter as f64,
+            Coloring::Distance { threshold } => match result.distance() {
+                Some(distance) => {
+                    let pixels = distance / ctx.pixel_size();
+                    if pixels < threshold { 0.0 } else { 1.0 - threshold/pixels }
+                },
+                None => 1.0,
+            },
+            Coloring::Angle => result.z.1.atan2(result.z.0) / (2.0*PI) + 0.5,
+            Coloring::Trap { scale } => match result.trap {
+                Some(distance) => 1.0 - (-distance/scale).exp(),
+                None => 1.0,
+            },
+            // The root goes in the integer part and the shade in the fraction,
+            // points without a root are negative
+            Coloring::Root => {
+                let shade = (result.smooth.ln_1p() / (max_iter as f64).ln_1p()).min(0.999);
+                match result.root {
+                    Some(root) => root as f64 + shade,
+                    None => -1.0 + shade,
+                }
+            },
+        })
     }
 
-    let mut pb = ProgressBar::new(ctx.y_px as u64);
-    pb.format("[=> ]");
-    pb.message("Rendering Rows ");
-    pb.add(0);
-    let mut handles = Vec::with_capacity(threads);
-    let (tx, rx) = spmc::channel();
-    let rc = Arc::new(frac);
-    for id in 0..threads {
-        let rx = rx.clone();
-        let mut histogram = histograms[id].clone();
-        let r = rc.clone();
-        handles.push(thread::spawn(move || {
-            let mut histogram = histogram.lock().unwrap();
-            loop {
-                match rx.recv().unwrap() {
-                    Some((row, row_arc)) => 
-                    {
-                        let row_arc : Arc<Mutex<Vec<u64>>> = row_arc;
-                        let mut row_iter = row_arc.lock().unwrap();
-                        for (x0, y0, x_px) in row {
-
-                            let iter = r(x0, y0, ctx.max_iter);
-
-                            row_iter[x_px as usize] = iter;
-                            if iter != ctx.max_iter { 
-                                histogram[iter as usize] += 1;
-                            }
-                        }
-                    },
-                    None => break,
-                }
-            }
-        }));
+    /// The color of a value that isn't histogram equalized.
+    pub fn color(&self, cs: &ColorScheme, value: f64) -> image::Rgb<u8> {
+        match *self {
+            Coloring::Root if value < 0.0 => cs.get_color(value + 1.0),
+            Coloring::Root => {
+                // Consecutive roots get hues a golden angle apart, so any
+                // number of them stay distinguishable
+                let hue = (value.trunc() * 0.618_033_988_75).fract();
+                hsv(hue, 0.8, 1.0 - value.fract())
+            },
+            _ => cs.get_color(value),
+        }
     }
 
-    for (row, y_px) in ctx.enumerate_rows() {
-        let mut row_iter =  iters[y_px as usize].clone();
-        tx.send(Some((row, row_iter))).unwrap();
+    fn equalized(&self) -> bool { *self == Coloring::Histogram }
+}
+
+/// How points inside the set are colored. Everything but `Solid` picks from
+/// the interior `ColorScheme`, which is the main one unless another was set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interior {
+    Solid(image::Rgb<u8>),
+    /// Period of the attracting cycle the orbit settled into. Consecutive
+    /// periods are spread far apart in the `ColorScheme`.
+    Period,
+    /// Final `|z|`, from 0 at the start of the `ColorScheme` to 2 at the end
+    Magnitude,
+    /// Estimated distance to the edge of the set, fading from the start of
+    /// the `ColorScheme` at the edge to the end `scale` or so pixels in
+    Distance { scale: f64 },
+}
+
+impl Default for Interior {
+    fn default() -> Interior { Interior::Solid(image::Rgb([0, 0, 0])) }
+}
+
+impl Interior {
+    /// The position in the interior `ColorScheme` of a point inside the set.
+    /// Points the formula couldn't say anything about go at the end.
+    pub fn value(&self, result: &EscapeResult, ctx: &RenderingContext) -> f64 {
+        match *self {
+            Interior::Solid(_) => 0.0,
+            Interior::Period => match result.period {
+                Some(period) => ((period - 1) as f64 * 0.618_033_988_75).fract(),
+                None => 1.0,
+            },
+            Interior::Magnitude => (result.z.0.hypot(result.z.1) / 2.0).min(1.0),
+            Interior::Distance { scale } => match result.interior_distance {
+                Some(distance) => 1.0 - (-distance / ctx.pixel_size() / scale).exp(),
+                None => 1.0,
+            },
+        }
     }
+}
+
+/// A pixel waiting to be colored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sample {
+    /// `Coloring::value` of a point outside the set
+    Outside(f64),
+    /// `Interior::value` of a point inside it
+    Inside(f64),
+}
 
-    for _ in 0..threads {
-        tx.send(None).unwrap();
+impl Sample {
+    pub fn new(result: &EscapeResult, coloring: Coloring, interior: Interior, ctx: &RenderingContext) -> Sample {
+        match coloring.value(result, ctx) {
+            Some(value) => Sample::Outside(value),
+            None => Sample::Inside(interior.value(result, ctx)),
+        }
     }
 
-    for handle in handles {
-        handle.join().unwrap();
+    pub fn outside(self) -> Option<f64> {
+        match self {
+            Sample::Outside(value) => Some(value),
+            Sample::Inside(_) => None,
+        }
     }
-    pb.finish();
+}
 
-    let mut histogram : Vec<u64> = Vec::with_capacity(ctx.max_iter as usize);
-    for _ in 0..ctx.max_iter { histogram.push(0); }
+/// Converts a color given as hue, saturation and value, all in `[0, 1]`.
+fn hsv(h: f64, s: f64, v: f64) -> image::Rgb<u8> {
+    let h = h * 6.0;
+    let f = h.fract();
+    let (p, q, t) = (v*(1.0 - s), v*(1.0 - s*f), v*(1.0 - s*(1.0 - f)));
+    let (r, g, b) = match h as u32 % 6 {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q),
+    };
+    image::Rgb([(r*255.0) as u8, (g*255.0) as u8, (b*255.0) as u8])
+}
 
-    let mut total = 0;
-    for i in 0..ctx.max_iter {
-        for j in 0..threads {
-            total += histograms[j as usize].lock().unwrap()[i as usize];
+/// Looks up a smooth escape value in a cumulative histogram, interpolating
+/// between the bins on either side of it.
+fn equalize(histogram: &[f64], iter: f64) -> f64 {
+    let i = (iter as usize).min(histogram.len() - 1);
+    let next = histogram[(i + 1).min(histogram.len() - 1)];
+    histogram[i] + (next - histogram[i]) * iter.fract()
+}
+
+/// Colors a row major buffer of samples.
+pub fn colorize(ctx: &RenderingContext, cs: &ColorScheme, values: &[Sample]) -> image::RgbImage {
+    let coloring = cs.coloring();
+    let mut histogram = vec![0.0; ctx.max_iter as usize];
+    if coloring.equalized() {
+        for value in values.iter().filter_map(|v| v.outside()) {
+            histogram[(value as usize).min(ctx.max_iter as usize - 1)] += 1.0;
+        }
+        let mut total = 0.0;
+        for bin in histogram.iter_mut() {
+            total += *bin;
+            *bin = total;
         }
-        histogram[i as usize] = total;
     }
 
     let mut img = ImageBuffer::new(ctx.x_px, ctx.y_px);
     for (x, y, pixel) in img.enumerate_pixels_mut() {
-        let mut iter = iters[y as usize].lock().unwrap()[x as usize];
-        if iter == ctx.max_iter {
-            *pixel = image::Rgb([0, 0, 0]);
-        } else {
-            let pos = histogram[iter as usize] as f64 / (ctx.x_px*ctx.y_px) as f64;
-            *pixel = cs.get_color(pos);
+        match values[x as usize + y as usize*ctx.x_px as usize] {
+            Sample::Inside(value) => *pixel = cs.interior_color(value),
+            Sample::Outside(value) if coloring.equalized() => {
+                let pos = equalize(&histogram, value) / values.len() as f64;
+                *pixel = cs.get_color(pos);
+            },
+            Sample::Outside(value) => *pixel = coloring.color(cs, value),
+        }
+    }
+    img
+}
+
+/// ```no_run
+/// # extern crate fractal;
+/// # use std::path::Path;
+/// # use fractal::{render_image, ColorScheme, Fractal, Monitor};
+/// # use fractal::formulas::{Mandelbrot, Julia};
+/// # fn main() {
+/// # let cs = ColorScheme::new();
+/// let monitor = Monitor::terminal();
+/// render_image(Mandelbrot.default_context(), &cs, &Path::new("test.png"), Mandelbrot, &monitor).unwrap();
+///
+/// let julia = Julia::new(0.0, 0.9);
+/// render_image(julia.default_context(), &cs, &Path::new("test.png"), julia, &monitor).unwrap();
+///
+/// render_image(Mandelbrot.default_context(), &cs, &Path::new("test.png"), |x0: f64, y0: f64, max_iter: u64| {
+///         Julia::new(x0, y0).iterate(0.0, 0.0, max_iter, 0.0)
+///     }, &monitor).unwrap();
+/// # }
+/// ```
+pub fn render_image<M, F>(ctx: RenderingContext, cs: &ColorScheme, path: &Path, frac: F, monitor: &Monitor) -> Result<(), Error> where F: IntoFractal<M> {
+    image::ImageRgb8(render_image_rgb(ctx, cs, frac, monitor)?).save(path)?;
+    Ok(())
+}
+
+/// `render_image` without the file, handing back the picture instead.
+///
+/// ```
+/// # extern crate fractal;
+/// # use fractal::{render_image_rgb, ColorScheme, Fractal, Monitor};
+/// # use fractal::formulas::Mandelbrot;
+/// # fn main() {
+/// let mut cs = ColorScheme::new();
+/// cs.add_hex(0xffffff, 0.0);
+/// cs.add_hex(0xffffff, 1.0);
+/// let mut ctx = Mandelbrot.default_context();
+/// ctx.x_px = 64;
+/// ctx.y_px = 64;
+/// let img = render_image_rgb(ctx, &cs, Mandelbrot, &Monitor::new()).unwrap();
+/// assert_eq!(img.dimensions(), (64, 64));
+/// // The middle is inside the set and gets the interior color, black by default
+/// assert_eq!(img.get_pixel(32, 32).data, [0, 0, 0]);
+/// assert_eq!(img.get_pixel(0, 0).data, [255, 255, 255]);
+/// # }
+/// ```
+pub fn render_image_rgb<M, F>(ctx: RenderingContext, cs: &ColorScheme, frac: F, monitor: &Monitor) -> Result<image::RgbImage, Error> where F: IntoFractal<M> {
+    let values = render_samples(ctx.clone(), cs, frac, monitor)?;
+    Ok(colorize(&ctx, cs, &values))
+}
+
+/// The row major buffer of samples `render_image` colors, valued by the
+/// coloring and interior of `cs`. Pass it to `colorize` for the picture.
+pub fn render_samples<M, F>(ctx: RenderingContext, cs: &ColorScheme, frac: F, monitor: &Monitor) -> Result<Vec<Sample>, Error> where F: IntoFractal<M> {
+    ctx.validate()?;
+    cs.validate()?;
+    let frac = frac.into_fractal(1);
+    match ctx.precision {
+        Precision::F64 => render_samples_as::<f64, _>(ctx, cs, frac, monitor),
+        Precision::DoubleDouble => render_samples_as::<DoubleDouble, _>(ctx, cs, frac, monitor),
+        Precision::BigFloat => render_samples_as::<BigFloat, _>(ctx, cs, frac, monitor),
+    }
+}
+
+fn render_samples_as<T: Real, F: Fractal + 'static>(ctx: RenderingContext, cs: &ColorScheme, frac: F, monitor: &Monitor) -> Result<Vec<Sample>, Error> {
+    let coloring = cs.coloring();
+    let interior = cs.interior();
+    let pixel = |x0: &T, y0: &T, _, _| {
+        let result = frac.escape_precise(x0, y0, ctx.max_iter, 0.0);
+        Sample::new(&result, coloring, interior, &ctx)
+    };
+    match ctx.strategy {
+        Strategy::EveryPixel => render_tiles(&ctx, monitor, pixel),
+        Strategy::Subdivide => render_subdivided(&ctx, monitor, num_cpus::get(), pixel),
+    }
+}
+
+/// Width and height of the blocks of pixels `render_tiles` hands out.
+const TILE: u32 = 16;
+
+/// Width and height of the blocks subdivision starts from, bigger so solid
+/// areas can be filled in fewer, larger rectangles.
+const SUBDIVIDE_TILE: u32 = 64;
+
+/// Rectangles this thin are worked out pixel by pixel rather than split.
+const MIN_SUBDIVIDE: u32 = 8;
+
+/// A block of pixels along with the pieces of the output its rows go into.
+struct Tile<'a, P: 'a> {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    rows: Vec<&'a mut [Option<P>]>,
+}
+
+/// Takes the next tile from the front of a worker's own queue, or once that
+/// runs dry steals one from the back of another worker's.
+fn next_tile<'a, P>(queues: &[Mutex<VecDeque<Tile<'a, P>>>], worker: usize) -> Result<Option<Tile<'a, P>>, Error> {
+    for i in 0..queues.len() {
+        let mut queue = queues[(worker + i) % queues.len()].lock()?;
+        let tile = if i == 0 { queue.pop_front() } else { queue.pop_back() };
+        if tile.is_some() { return Ok(tile) }
+    }
+    Ok(None)
+}
+
+/// Splits `ctx` into square tiles, works them out on a thread per core and
+/// collects what `pixel` makes of every point into a row major buffer.
+/// `pixel` gets the coordinates of the point followed by its pixel.
+///
+/// Every worker starts on its own run of neighbouring tiles and steals from
+/// the others when it finishes early, so views where some parts take far
+/// longer than others keep every core busy to the end. Each tile writes
+/// straight into its own pieces of the buffer.
+pub fn render_tiles<T, P, F>(ctx: &RenderingContext, monitor: &Monitor, pixel: F) -> Result<Vec<P>, Error>
+        where T: Real, P: Send, F: Fn(&T, &T, u32, u32) -> P + Sync {
+    render_tiles_on(ctx, monitor, num_cpus::get(), pixel)
+}
+
+/// `render_tiles` on `threads` threads.
+fn render_tiles_on<T, P, F>(ctx: &RenderingContext, monitor: &Monitor, threads: usize, pixel: F) -> Result<Vec<P>, Error>
+        where T: Real, P: Send, F: Fn(&T, &T, u32, u32) -> P + Sync {
+    ctx.validate()?;
+    schedule(ctx, monitor, threads, TILE, |tile| {
+        let points = ctx.enumerate_tile_as::<T>(tile.x, tile.y, tile.width, tile.height);
+        for (dest, (row, y_px)) in tile.rows.iter_mut().zip(points) {
+            for (value, (x0, y0, x_px)) in dest.iter_mut().zip(row) {
+                *value = Some(pixel(&x0, &y0, x_px, y_px));
+            }
+        }
+    })
+}
+
+/// `render_tiles_on` working out each tile by `Strategy::Subdivide`.
+fn render_subdivided<T, P, F>(ctx: &RenderingContext, monitor: &Monitor, threads: usize, pixel: F) -> Result<Vec<P>, Error>
+        where T: Real, P: Send + Clone + PartialEq, F: Fn(&T, &T, u32, u32) -> P + Sync {
+    schedule(ctx, monitor, threads, SUBDIVIDE_TILE, |tile| {
+        let points = ctx.points_as::<T>();
+        let (width, height) = (tile.width, tile.height);
+        subdivide(tile, &points, &pixel, (0, 0), (width - 1, height - 1));
+    })
+}
+
+/// Mariani-Silver on the rectangle of a tile from `min` to `max`, both
+/// included and relative to the tile's corner.
+fn subdivide<T, P, F>(tile: &mut Tile<P>, points: &Points<T>, pixel: &F, min: (u32, u32), max: (u32, u32))
+        where T: Real, P: Clone + PartialEq, F: Fn(&T, &T, u32, u32) -> P {
+    let ((x0, y0), (x1, y1)) = (min, max);
+    let mut fill : Option<P> = None;
+    let mut uniform = true;
+    for x in x0..=x1 {
+        uniform &= matches(tile, points, pixel, &mut fill, x, y0);
+        if y1 > y0 { uniform &= matches(tile, points, pixel, &mut fill, x, y1); }
+    }
+    for y in y0 + 1..y1 {
+        uniform &= matches(tile, points, pixel, &mut fill, x0, y);
+        if x1 > x0 { uniform &= matches(tile, points, pixel, &mut fill, x1, y); }
+    }
+
+    // Nothing left inside
+    if x1 - x0 < 2 || y1 - y0 < 2 { return }
+
+    // A filament that slipped between the pixels of the border is caught if
+    // it crosses the middle. Splitting would need the middle anyway.
+    let (mid_x, mid_y) = ((x0 + x1)/2, (y0 + y1)/2);
+    uniform = uniform
+        && (x0 + 1..x1).all(|x| matches(tile, points, pixel, &mut fill, x, mid_y))
+        && (y0 + 1..y1).all(|y| matches(tile, points, pixel, &mut fill, mid_x, y));
+
+    if let (true, Some(fill)) = (uniform, fill) {
+        for row in &mut tile.rows[y0 as usize + 1..y1 as usize] {
+            for value in &mut row[x0 as usize + 1..x1 as usize] {
+                *value = Some(fill.clone());
+            }
         }
+    } else if x1 - x0 <= MIN_SUBDIVIDE || y1 - y0 <= MIN_SUBDIVIDE {
+        // Splitting again would cost about as much as the pixels left
+        for y in y0 + 1..y1 {
+            for x in x0 + 1..x1 {
+                let (x_px, y_px) = (tile.x + x, tile.y + y);
+                let (x0, y0) = points.at(x_px, y_px);
+                tile.rows[y as usize][x as usize].get_or_insert_with(|| pixel(&x0, &y0, x_px, y_px));
+            }
+        }
+    } else if x1 - x0 >= y1 - y0 {
+        subdivide(tile, points, pixel, (x0, y0), (mid_x, y1));
+        subdivide(tile, points, pixel, (mid_x, y0), (x1, y1));
+    } else {
+        subdivide(tile, points, pixel, (x0, y0), (x1, mid_y));
+        subdivide(tile, points, pixel, (x0, mid_y), (x1, y1));
     }
+}
 
-    image::ImageRgb8(img).save(path).unwrap();
+/// Works out the pixel at `(x, y)` in the tile unless it already is, and
+/// whether it matches `fill`, which the first pixel checked becomes.
+fn matches<T, P, F>(tile: &mut Tile<P>, points: &Points<T>, pixel: &F, fill: &mut Option<P>, x: u32, y: u32) -> bool
+        where T: Real, P: Clone + PartialEq, F: Fn(&T, &T, u32, u32) -> P {
+    let (x_px, y_px) = (tile.x + x, tile.y + y);
+    let value = tile.rows[y as usize][x as usize].get_or_insert_with(|| {
+        let (x0, y0) = points.at(x_px, y_px);
+        pixel(&x0, &y0, x_px, y_px)
+    });
+    match *fill {
+        Some(ref fill) => fill == value,
+        None => {
+            *fill = Some(value.clone());
+            true
+        },
+    }
 }
 
-pub fn render_animation<F>(ctx: RenderingContext, cs: ColorScheme, path: &'static Path, frames: u32, frac: F) where F: Fn(f64, f64, u64, u32) -> u64 + Send + Sync + 'static{
+/// Cuts the output into `size` pixel tiles and has `threads` threads hand
+/// them to `work` until every one is done.
+fn schedule<P, W>(ctx: &RenderingContext, monitor: &Monitor, threads: usize, size: u32, work: W) -> Result<Vec<P>, Error>
+        where P: Send, W: Fn(&mut Tile<P>) + Sync {
+    let mut values : Vec<Option<P>> = (0..ctx.x_px as usize*ctx.y_px as usize).map(|_| None).collect();
+    if values.is_empty() { return Ok(Vec::new()) }
+
+    let across = ctx.x_px.div_ceil(size);
+    let down = ctx.y_px.div_ceil(size);
+    let mut tiles = Vec::with_capacity(across as usize*down as usize);
+    for y in 0..down {
+        for x in 0..across {
+            tiles.push(Tile {
+                x: x*size, y: y*size,
+                width: size.min(ctx.x_px - x*size), height: size.min(ctx.y_px - y*size),
+                rows: Vec::with_capacity(size as usize),
+            });
+        }
+    }
+    for (y, row) in values.chunks_mut(ctx.x_px as usize).enumerate() {
+        for (x, piece) in row.chunks_mut(size as usize).enumerate() {
+            tiles[y/size as usize*across as usize + x].rows.push(piece);
+        }
+    }
+
+    let stage = monitor.phase(Phase::Rendering, tiles.len() as u64);
+    let per_thread = tiles.len().div_ceil(threads);
+    let mut tiles = tiles.into_iter();
+    let queues : Vec<Mutex<VecDeque<Tile<P>>>> = (0..threads)
+        .map(|_| Mutex::new(tiles.by_ref().take(per_thread).collect()))
+        .collect();
+
+    let outcomes : Vec<Result<(), Error>> = thread::scope(|scope| {
+        let handles : Vec<_> = (0..threads).map(|worker| {
+            let (queues, stage, work) = (&queues, &stage, &work);
+            scope.spawn(move || {
+                while let Some(mut tile) = next_tile(queues, worker)? {
+                    work(&mut tile);
+                    stage.step()?;
+                }
+                Ok(())
+            })
+        }).collect();
+        // Every thread is joined before looking at any of them, otherwise
+        // the scope would panic along with any that did
+        handles.into_iter().map(|handle| handle.join().unwrap_or(Err(Error::Worker))).collect()
+    });
+    for outcome in outcomes {
+        outcome?;
+    }
+
+    values.into_iter().map(|value| value.ok_or(Error::Worker)).collect()
+}
+
+/// Renders `frames` pictures into the folder `path`, named `frame0.png` and on.
+pub fn render_animation<M, F>(ctx: RenderingContext, cs: ColorScheme, path: &Path, frames: u32, frac: F, monitor: &Monitor) -> Result<(), Error> where F: IntoFractal<M> {
+    let path = path.to_path_buf();
+    render_animation_with(ctx, cs, frames, frac, monitor, move |frame, img| {
+        image::ImageRgb8(img).save(path.join(Path::new(&format!("frame{}.png", frame))))?;
+        Ok(())
+    })
+}
+
+/// `render_animation` without the files, handing back every frame in order.
+pub fn render_animation_rgb<M, F>(ctx: RenderingContext, cs: ColorScheme, frames: u32, frac: F, monitor: &Monitor) -> Result<Vec<image::RgbImage>, Error> where F: IntoFractal<M> {
+    let images = Arc::new(Mutex::new((0..frames).map(|_| None).collect::<Vec<_>>()));
+    let dest = images.clone();
+    render_animation_with(ctx, cs, frames, frac, monitor, move |frame, img| {
+        dest.lock()?[frame as usize] = Some(img);
+        Ok(())
+    })?;
+    let mut images = images.lock()?;
+    images.drain(..).map(|img| img.ok_or(Error::Worker)).collect()
+}
+
+/// `render_animation` handing each frame and its number to `sink` as soon
+/// as it is colored. Frames arrive out of order, on whichever worker thread
+/// colored them, which lets them be written out or encoded in parallel.
+/// Frames keep coming after `sink` fails, and the first error it gave is
+/// returned at the end.
+pub fn render_animation_with<M, F, S>(ctx: RenderingContext, cs: ColorScheme, frames: u32, frac: F, monitor: &Monitor, sink: S) -> Result<(), Error>
+        where F: IntoFractal<M>, S: Fn(u32, image::RgbImage) -> Result<(), Error> + Send + Sync + 'static {
+    ctx.validate()?;
+    cs.validate()?;
+    let frac = frac.into_fractal(frames);
+    match ctx.precision {
+        Precision::F64 => render_animation_as::<f64, _, _>(ctx, cs, frames, frac, monitor, sink),
+        Precision::DoubleDouble => render_animation_as::<DoubleDouble, _, _>(ctx, cs, frames, frac, monitor, sink),
+        Precision::BigFloat => render_animation_as::<BigFloat, _, _>(ctx, cs, frames, frac, monitor, sink),
+    }
+}
+
+fn render_animation_as<T, F, S>(ctx: RenderingContext, cs: ColorScheme, frames: u32, frac: F, monitor: &Monitor, sink: S) -> Result<(), Error>
+        where T: Real, F: Fractal + 'static, S: Fn(u32, image::RgbImage) -> Result<(), Error> + Send + Sync + 'static {
     let mut handles = Vec::with_capacity(num_cpus::get());
     let (tx, rx) = spmc::channel();
     let frac = Arc::new(frac);
+    let coloring = cs.coloring();
+    let interior = cs.interior();
     let cs = Arc::new(cs);
 
-    let mut pb = ProgressBar::new(frames as u64);
-    pb.format("[=> ]");
-    pb.message("Allocating images ");
+    let stage = monitor.phase(Phase::Allocating, frames as u64);
     let mut images = Vec::with_capacity(frames as usize);
     for _ in 0..frames {
-        let mut image = Vec::with_capacity(ctx.x_px as usize*ctx.y_px as usize);
-        for _ in 0..ctx.x_px*ctx.y_px { image.push(0); }
-        images.push(Arc::new(Mutex::new(image)));
-        pb.inc();
+        images.push(Arc::new(Mutex::new(vec![Sample::Inside(0.0); ctx.x_px as usize*ctx.y_px as usize])));
+        stage.step()?;
     }
-    pb.finish();
     
-    let mut histograms : Vec<Arc<Mutex<Vec<u64>>>> = Vec::with_capacity(num_cpus::get());
+    let mut histograms : Vec<Arc<Mutex<Vec<f64>>>> = Vec::with_capacity(num_cpus::get());
     for _ in 0..num_cpus::get() {
-        let mut histogram = Vec::with_capacity(ctx.max_iter as usize);
-        for _ in 0..ctx.max_iter { histogram.push(0); }
-        histograms.push(Arc::new(Mutex::new(histogram)));
+        histograms.push(Arc::new(Mutex::new(vec![0.0; ctx.max_iter as usize])));
     }
 
 
-    let mut pb = ProgressBar::new(frames as u64);
-    pb.format("[=> ]");
-    pb.message("Rendering frames ");
-    pb.add(0);
-    let pb = Arc::new(Mutex::new(pb));
-    for i in 0..num_cpus::get() {
+    let stage = Arc::new(monitor.phase(Phase::Rendering, frames as u64));
+    for histogram in &histograms {
         let rx = rx.clone();
+        let ctx = ctx.clone();
         let frac = frac.clone();
-        let histogram = histograms[i].clone();
-        let pb = pb.clone();
+        let histogram = histogram.clone();
+        let stage = stage.clone();
         handles.push(thread::spawn(move || {
-            let mut histogram = histogram.lock().unwrap();
-            loop {
-                match rx.recv().unwrap() {
-                    Some((dest, frame)) =>
-                    {
-                        let dest : Arc<Mutex<Vec<u64>>> = dest;
-                        let mut image = dest.lock().unwrap();
-
-                        for (x0, y0, x_px, y_px) in ctx.enumerate_points() {
-                            let iter = frac(x0, y0, ctx.max_iter, frame);
-                            image[x_px as usize + y_px as usize*ctx.x_px as usize] = iter;
-                        }
+            let mut histogram = histogram.lock()?;
+            while let Some((dest, frame)) = rx.recv()? {
+                let dest : Arc<Mutex<Vec<Sample>>> = dest;
+                let frame : u32 = frame;
+                let mut image = dest.lock()?;
+                let t = frame as f64 / frames as f64;
+
+                for (x0, y0, x_px, y_px) in ctx.enumerate_points_as::<T>() {
+                    let result = frac.escape_precise(&x0, &y0, ctx.max_iter, t);
+                    image[x_px as usize + y_px as usize*ctx.x_px as usize] = Sample::new(&result, coloring, interior, &ctx);
+                }
 
-                        for (_, _, x_px, y_px) in ctx.enumerate_points() {
-                            let iter = image[x_px as usize + y_px as usize*ctx.x_px as usize];
-                            if iter == ctx.max_iter { continue; }
-                            let mut conv = 0;
-                            for a in 0..3 {
-                                for b in 0..3 {
-                                    let a = a as i32 - 1;
-                                    let b = b as i32 - 1;
-                                    if a == 0 && b == 0 { continue; }
-                                    if x_px as i32 + a < 0 || x_px as i32 + a >= ctx.x_px as i32 { continue; }
-                                    if y_px as i32 + b < 0 || y_px as i32 + b >= ctx.y_px as i32 { continue; }
-                                    let index = (x_px as i32 + a + (y_px as i32 + b)*ctx.x_px as i32) as usize;
-                                    conv += (iter as i64 - image[index] as i64).abs();
-                                }
-                            }
-                            histogram[iter as usize] += conv as u64
+                for (_, _, x_px, y_px) in ctx.enumerate_points() {
+                    let iter = match image[x_px as usize + y_px as usize*ctx.x_px as usize] {
+                        Sample::Outside(iter) => iter,
+                        Sample::Inside(_) => continue,
+                    };
+                    let mut conv = 0.0;
+                    for a in 0..3 {
+                        for b in 0..3 {
+                            let a = a - 1;
+                            let b = b - 1;
+                            if a == 0 && b == 0 { continue; }
+                            if x_px as i32 + a < 0 || x_px as i32 + a >= ctx.x_px as i32 { continue; }
+                            if y_px as i32 + b < 0 || y_px as i32 + b >= ctx.y_px as i32 { continue; }
+                            let index = (x_px as i32 + a + (y_px as i32 + b)*ctx.x_px as i32) as usize;
+                            conv += (iter - image[index].outside().unwrap_or(ctx.max_iter as f64)).abs();
                         }
-
-                        pb.lock().unwrap().inc();
-                    },
-                    None => break,
+                    }
+                    histogram[(iter as usize).min(ctx.max_iter as usize - 1)] += conv
                 }
+
+                stage.step()?;
             }
+            Ok(())
         }));
 
     }
 
     for i in 0..frames {
-        tx.send(Some((images[i as usize].clone(), i))).unwrap();
+        if tx.send(Some((images[i as usize].clone(), i))).is_err() { break }
     }
 
     for _ in 0..handles.len(){
-        tx.send(None).unwrap();
+        let _ = tx.send(None);
     }
 
-    for thread in handles {
-        thread.join().unwrap();
-    }
-
-    pb.lock().unwrap().finish_print("done");
+    join(handles)?;
 
-    let mut histogram : Vec<u64> = Vec::with_capacity(ctx.max_iter as usize);
-    for _ in 0..ctx.max_iter { histogram.push(0); }
+    let mut histogram : Vec<f64> = vec![0.0; ctx.max_iter as usize];
 
-    let mut total : u64 = 0;
+    let mut total : f64 = 0.0;
     for i in 0..ctx.max_iter {
-        for j in 0..histograms.len() {
-            total += histograms[j].lock().unwrap()[i as usize];
+        for hist in &histograms {
+            total += hist.lock()?[i as usize];
         }
         histogram[i as usize] = total;
     }
@@ -262,53 +595,213 @@ pub fn render_animation<F>(ctx: RenderingContext, cs: ColorScheme, path: &'stati
     let mut handles = Vec::with_capacity(num_cpus::get());
     let (tx, rx) = spmc::channel();
 
-    let mut pb = ProgressBar::new(frames as u64);
-    pb.format("[=> ]");
-    pb.message("Writing images ");
-    pb.add(0);
-    let pb = Arc::new(Mutex::new(pb));
+    let stage = Arc::new(monitor.phase(Phase::Coloring, frames as u64));
     let histogram = Arc::new(histogram);
+    let sink = Arc::new(sink);
     for _ in 0..num_cpus::get() {
         let rx = rx.clone();
+        let ctx = ctx.clone();
         let histogram = histogram.clone();
         let cs = cs.clone();
-        let pb = pb.clone();
+        let sink = sink.clone();
+        let stage = stage.clone();
         handles.push(thread::spawn(move || {
-            loop {
-                match rx.recv().unwrap() {
-                    Some((img, frame)) => {
-                        let img : Arc<Mutex<Vec<u64>>> = img;
-                        let image = img.lock().unwrap();
-                        let mut img = image::ImageBuffer::new(ctx.x_px, ctx.y_px);
-                        for (x, y, pixel) in img.enumerate_pixels_mut() {
-                            let mut iter = image[x as usize + y as usize*ctx.x_px as usize];
-                            if iter == ctx.max_iter {
-                                *pixel = image::Rgb([0, 0, 0]);
-                            } else {
-                                let pos = histogram[iter as usize] as f64 / total as f64;
-                                *pixel = cs.get_color(pos);
-                            }
-                        }
-                        image::ImageRgb8(img).save(path.join(Path::new(&format!("frame{}.png", frame)))).unwrap();
-                        pb.lock().unwrap().inc();
-                    },
-                    None => break,
+            // A frame that can't be written shouldn't lose the ones after it
+            let mut failed = Ok(());
+            while let Some((img, frame)) = rx.recv()? {
+                let img : Arc<Mutex<Vec<Sample>>> = img;
+                let frame : u32 = frame;
+                let image = img.lock()?;
+                let mut img = image::ImageBuffer::new(ctx.x_px, ctx.y_px);
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    match image[x as usize + y as usize*ctx.x_px as usize] {
+                        Sample::Inside(value) => *pixel = cs.interior_color(value),
+                        Sample::Outside(value) if coloring.equalized() => {
+                            let pos = equalize(&histogram, value) / total;
+                            *pixel = cs.get_color(pos);
+                        },
+                        Sample::Outside(value) => *pixel = coloring.color(&cs, value),
+                    }
                 }
+                if let Err(err) = sink(frame, img) {
+                    if failed.is_ok() { failed = Err(err); }
+                }
+                stage.step()?;
             }
+            failed
         }));
     }
 
     for i in 0..frames {
-        tx.send(Some((images[i as usize].clone(), i))).unwrap();
+        if tx.send(Some((images[i as usize].clone(), i))).is_err() { break }
     }
 
     for _ in 0..handles.len() {
-        tx.send(None).unwrap();
+        let _ = tx.send(None);
     }
 
-    for thread in handles {
-        thread.join().unwrap();
-    }
-    pb.lock().unwrap().finish();
+    join(handles)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use super::{render_samples, render_tiles_on, render_subdivided, render_animation_rgb, render_animation_with, Coloring};
+    use super::super::{ColorScheme, Error, Fractal, IntoFractal, Monitor, RenderingContext, Strategy};
+    use super::super::formulas::{Julia, Mandelbrot, Newton};
+
+    /// Renders with both strategies and checks that subdividing gets at most
+    /// one pixel in a thousand wrong, as `Strategy::Subdivide` promises.
+    fn subdivide_matches<M, F: IntoFractal<M> + Clone>(mut ctx: RenderingContext, cs: &ColorScheme, frac: F) {
+        ctx.strategy = Strategy::EveryPixel;
+        let every = render_samples(ctx.clone(), cs, frac.clone(), &Monitor::new()).unwrap();
+        ctx.strategy = Strategy::Subdivide;
+        let subdivided = render_samples(ctx, cs, frac, &Monitor::new()).unwrap();
+        let differing = every.iter().zip(&subdivided).filter(|&(a, b)| a != b).count();
+        assert!(differing <= every.len()/1000, "{} of {} pixels differ", differing, every.len());
+    }
+
+    fn view(x: f64, y: f64, scale: f64) -> RenderingContext {
+        let mut ctx = Mandelbrot.default_context();
+        ctx.x = x.into();
+        ctx.y = y.into();
+        ctx.scale = scale;
+        ctx.x_px = 300;
+        ctx.y_px = 200;
+        ctx.max_iter = 1000;
+        ctx
+    }
+
+    fn gray() -> ColorScheme {
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0x000000, 0.0);
+        cs.add_hex(0xffffff, 1.0);
+        cs
+    }
+
+    #[test]
+    fn subdivide_minibrot() {
+        subdivide_matches(view(-1.7685, 0.0, 0.01), &gray(), Mandelbrot);
+        subdivide_matches(view(-1.76, 0.0, 0.05), &gray(), Mandelbrot);
+    }
+
+    #[test]
+    fn subdivide_seahorse_valley_by_distance() {
+        for &threshold in &[0.5, 2.0] {
+            let mut cs = gray();
+            cs.set_coloring(Coloring::Distance { threshold });
+            for &scale in &[0.2, 0.05, 0.02] {
+                subdivide_matches(view(-0.745, 0.11, scale), &cs, Mandelbrot);
+            }
+        }
+    }
+
+    #[test]
+    fn subdivide_julia_counts() {
+        for &(cx, cy) in &[(-0.8, 0.156), (-0.4, 0.6), (0.285, 0.01), (-0.7269, 0.1889)] {
+            let julia = Julia::new(cx, cy);
+            let count = move |x0: f64, y0: f64, max_iter: u64| julia.iterate(x0, y0, max_iter, 0.0);
+            let mut ctx = julia.default_context();
+            ctx.x_px = 300;
+            ctx.y_px = 200;
+            subdivide_matches(ctx, &gray(), count);
+        }
+    }
+
+    #[test]
+    fn root_coloring_shades_points_without_a_root() {
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0x000000, 0.0);
+        cs.add_hex(0xffffff, 1.0);
+        let cubic = Newton::from_roots(&[(1.0, 0.0), (-0.5, 0.866), (-0.5, -0.866)]);
+        let ctx = cubic.default_context();
+
+        let result = cubic.escape(0.9, 0.1, ctx.max_iter, 0.0);
+        let value = Coloring::Root.value(&result, &ctx).unwrap();
+        assert_eq!(value.trunc(), result.root.unwrap() as f64);
+
+        let nova = cubic.nova();
+        let result = nova.escape(0.1, 0.1, ctx.max_iter, 0.0);
+        assert!(result.escaped);
+        assert_eq!(result.root, None);
+        let value = Coloring::Root.value(&result, &ctx).unwrap();
+        assert!(value < 0.0);
+        assert_eq!(Coloring::Root.color(&cs, value), cs.get_color(value + 1.0));
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_picture() {
+        let ctx = view(-0.745, 0.11, 0.05);
+        let pixel = |x0: &f64, y0: &f64, _, _| {
+            let result = Mandelbrot.escape(*x0, *y0, ctx.max_iter, 0.0);
+            (result.iter, result.smooth.to_bits())
+        };
+        let single = render_tiles_on(&ctx, &Monitor::new(), 1, pixel).unwrap();
+        let subdivided = render_subdivided(&ctx, &Monitor::new(), 1, pixel).unwrap();
+        assert_eq!(single.len(), 300*200);
+        for &threads in &[2, 4, 7, 32] {
+            assert_eq!(render_tiles_on(&ctx, &Monitor::new(), threads, pixel).unwrap(), single);
+            assert_eq!(render_subdivided(&ctx, &Monitor::new(), threads, pixel).unwrap(), subdivided);
+        }
+    }
+
+    /// An 8 by 8 animation where every point of frame 1 is inside and
+    /// every point of the others escapes straight away.
+    fn blink() -> (RenderingContext, ColorScheme, impl Fn(f64, f64, u64, u32) -> u64 + Send + Sync + 'static) {
+        let mut ctx = view(0.0, 0.0, 4.0);
+        ctx.x_px = 8;
+        ctx.y_px = 8;
+        ctx.max_iter = 10;
+        let mut cs = ColorScheme::new();
+        cs.add_hex(0xffffff, 0.0);
+        cs.add_hex(0xffffff, 1.0);
+        (ctx, cs, |_: f64, _: f64, max_iter: u64, frame: u32| if frame == 1 { max_iter } else { 1 })
+    }
+
+    #[test]
+    fn animation_frames_come_back_in_order() {
+        let (ctx, cs, frac) = blink();
+        let frames = render_animation_rgb(ctx, cs, 3, frac, &Monitor::new()).unwrap();
+        assert_eq!(frames.len(), 3);
+        for (frame, img) in frames.iter().enumerate() {
+            assert_eq!(img.dimensions(), (8, 8));
+            let color = if frame == 1 { [0, 0, 0] } else { [255, 255, 255] };
+            assert!(img.pixels().all(|pixel| pixel.data == color), "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn animation_sink_gets_every_frame_once() {
+        let (ctx, cs, frac) = blink();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        render_animation_with(ctx, cs, 5, frac, &Monitor::new(), move |frame, img| {
+            assert_eq!(img.dimensions(), (8, 8));
+            seen.lock().unwrap().push(frame);
+            Ok(())
+        }).unwrap();
+        // Frames are colored in parallel, so they may arrive in any order
+        let mut calls = calls.lock().unwrap().clone();
+        calls.sort();
+        assert_eq!(calls, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn animation_sink_errors_come_back() {
+        let (ctx, cs, frac) = blink();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let result = render_animation_with(ctx, cs, 4, frac, &Monitor::new(), move |frame, _| {
+            seen.lock().unwrap().push(frame);
+            if frame == 2 { return Err(io::Error::other("disk full").into()) }
+            Ok(())
+        });
+        match result {
+            Err(Error::Io(err)) => assert_eq!(err.to_string(), "disk full"),
+            other => panic!("{:?}", other),
+        }
+        // The frames after the one that failed still arrive
+        assert_eq!(calls.lock().unwrap().len(), 4);
+    }
+}